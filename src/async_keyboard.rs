@@ -0,0 +1,518 @@
+use std::cell::{RefCell, RefMut};
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::rc::Rc;
+use futures::{Async, Future, Poll, Stream};
+use futures::task::{self, Task};
+use mio::{Evented, Poll as MioPoll, PollOpt, Ready, Token};
+use mio::unix::EventedFd;
+use tokio_core::reactor::{Handle as ReactorHandle, PollEvented, Timeout};
+use libc;
+use libusb::Error as UsbError;
+use error::{Error, Result};
+use color::{Color, KeyColor};
+use event::KeyEvent;
+use keyboard::{Keyboard, KeyboardImpl};
+
+// file descriptor of the transport, which is owned and closed by the transport
+struct Fd(RawFd);
+
+impl Evented for Fd {
+    fn register(&self, poll: &MioPoll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.0).register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &MioPoll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.0).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &MioPoll) -> io::Result<()> {
+        EventedFd(&self.0).deregister(poll)
+    }
+}
+
+struct Driver {
+    keyboard: KeyboardImpl,
+    reactor: ReactorHandle,
+    // pollfds the registrations were created for
+    pollfds: Vec<(RawFd, i16)>,
+    registrations: Vec<PollEvented<Fd>>,
+    // wakes us up for timed handlers and ack deadlines
+    timeout: Option<Timeout>,
+    events: VecDeque<KeyEvent>,
+    // tasks waiting for key events or acknowledgements
+    tasks: Vec<Task>,
+    reconnecting: bool,
+    // wakes us up for the next reconnect step
+    reconnect_timeout: Option<Timeout>,
+    // whether the last reconnect ran out of attempts
+    reconnect_failed: bool,
+}
+
+impl Driver {
+    /// Handles all completed transfers and registers the current task to be woken up
+    /// once more transfers complete.
+    ///
+    /// If the connection is lost and auto reconnect is enabled, reconnects on the reactor
+    /// instead. Fails once all reconnect attempts have failed.
+    fn drive(&mut self) -> Result<()> {
+        loop {
+            if self.reconnecting {
+                match try!(self.poll_reconnect()) {
+                    Async::Ready(()) => {},
+                    Async::NotReady => return Ok(()),
+                }
+            }
+            match self.handle_transfers() {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    if !self.keyboard.connection_lost(&e) {
+                        return Err(e);
+                    }
+                    self.start_reconnect();
+                },
+            }
+        }
+    }
+
+    fn start_reconnect(&mut self) {
+        self.keyboard.begin_reconnect();
+        self.reconnecting = true;
+        self.reconnect_failed = false;
+        self.reconnect_timeout = None;
+        self.timeout = None;
+        // file descriptors may be reused by the new connection, so register them again
+        self.pollfds.clear();
+        self.registrations.clear();
+    }
+
+    /// Makes reconnect attempts until the keyboard is reconnected or the next attempt is due later.
+    fn poll_reconnect(&mut self) -> Poll<(), Error> {
+        loop {
+            match self.reconnect_timeout {
+                Some(ref mut timeout) => match try!(timeout.poll().map_err(Error::from)) {
+                    Async::Ready(()) => {},
+                    Async::NotReady => return Ok(Async::NotReady),
+                },
+                None => {}
+            }
+            match self.keyboard.reconnect_step() {
+                Ok(None) => {
+                    self.reconnecting = false;
+                    self.reconnect_timeout = None;
+                    self.notify();
+                    return Ok(Async::Ready(()));
+                },
+                Ok(Some(delay)) => {
+                    let timeout = try!(Timeout::new(delay, &self.reactor).map_err(Error::from));
+                    self.reconnect_timeout = Some(timeout);
+                },
+                Err(e) => {
+                    self.reconnecting = false;
+                    self.reconnect_failed = true;
+                    self.reconnect_timeout = None;
+                    self.notify();
+                    return Err(e);
+                },
+            }
+        }
+    }
+
+    fn handle_transfers(&mut self) -> Result<()> {
+        try!(self.update_registrations());
+        loop {
+            let mut progress = false;
+            while let Some(events) = try!(self.keyboard.poll_events()) {
+                progress = true;
+                self.events.extend(events);
+            }
+            if progress {
+                self.notify();
+            }
+            // readiness is edge triggered, so we must handle transfers again after clearing it
+            let mut ready = false;
+            for (registration, &(_, events)) in self.registrations.iter_mut().zip(self.pollfds.iter()) {
+                let interest = interest(events);
+                if registration.poll_ready(interest).is_ready() {
+                    ready = true;
+                    if interest.is_readable() {
+                        try!(registration.need_read().map_err(Error::from));
+                    }
+                    if interest.is_writable() {
+                        try!(registration.need_write().map_err(Error::from));
+                    }
+                }
+            }
+            if !ready {
+                break;
+            }
+        }
+        self.update_timeout()
+    }
+
+    fn update_registrations(&mut self) -> Result<()> {
+        let pollfds = self.keyboard.pollfds();
+        if pollfds == self.pollfds {
+            return Ok(());
+        }
+        self.registrations.clear();
+        for &(fd, _) in pollfds.iter() {
+            let registration = try!(PollEvented::new(Fd(fd), &self.reactor).map_err(Error::from));
+            self.registrations.push(registration);
+        }
+        self.pollfds = pollfds;
+        Ok(())
+    }
+
+    fn update_timeout(&mut self) -> Result<()> {
+        self.timeout = match self.keyboard.next_timeout() {
+            Some(dur) => {
+                let mut timeout = try!(Timeout::new(dur, &self.reactor).map_err(Error::from));
+                match timeout.poll() {
+                    Ok(Async::NotReady) => {},
+                    // already due, poll again right away
+                    _ => task::current().notify(),
+                }
+                Some(timeout)
+            },
+            None => None,
+        };
+        Ok(())
+    }
+
+    fn park(&mut self) {
+        if !self.tasks.iter().any(|t| t.will_notify_current()) {
+            self.tasks.push(task::current());
+        }
+    }
+
+    fn notify(&mut self) {
+        for task in self.tasks.drain(..) {
+            task.notify();
+        }
+    }
+}
+
+/// Async façade of a keyboard running on a tokio-core reactor.
+///
+/// The keyboard is driven by the file descriptors of its transport, so no thread is needed.
+/// As a `Stream` it yields all parsed key events, which are dispatched to the keyboard's
+/// handlers as well. The stream never ends.
+///
+/// If the connection is lost and auto reconnect is enabled, the keyboard is reconnected
+/// according to its reconnect policy without blocking the reactor, and its colors are restored.
+/// The stream yields an error if the connection is lost without auto reconnect
+/// or if all reconnect attempts fail. Polling it again afterwards starts reconnecting again.
+///
+/// Key events are buffered until the stream is polled.
+/// Transports without file descriptors like `FakeDevice` are only checked
+/// when the stream or a future is polled for another reason.
+pub struct AsyncKeyboard {
+    driver: Rc<RefCell<Driver>>,
+}
+
+impl AsyncKeyboard {
+    /// Wraps given keyboard, calling `init` of all its handlers.
+    pub fn new(mut keyboard: KeyboardImpl, reactor: &ReactorHandle) -> Result<AsyncKeyboard> {
+        try!(keyboard.init_handlers());
+        Ok(AsyncKeyboard {
+            driver: Rc::new(RefCell::new(Driver {
+                keyboard: keyboard,
+                reactor: reactor.clone(),
+                pollfds: Vec::new(),
+                registrations: Vec::new(),
+                timeout: None,
+                events: VecDeque::new(),
+                tasks: Vec::new(),
+                reconnecting: false,
+                reconnect_timeout: None,
+                reconnect_failed: false,
+            })),
+        })
+    }
+
+    /// Returns the wrapped keyboard, e.g. to add handlers or change settings.
+    pub fn keyboard(&self) -> RefMut<KeyboardImpl> {
+        RefMut::map(self.driver.borrow_mut(), |driver| &mut driver.keyboard)
+    }
+
+    /// Sets the colors of given keys.
+    ///
+    /// The returned future resolves once the keyboard has acknowledged the colors.
+    pub fn set_key_colors(&self, key_colors: Vec<KeyColor>) -> ColorsAcknowledged {
+        let mut driver = self.driver.borrow_mut();
+        let error = match driver.keyboard.set_key_colors(key_colors) {
+            // the colors are restored once reconnected
+            Err(Error::Usb(_)) if driver.reconnecting => None,
+            res => res.err(),
+        };
+        ColorsAcknowledged {
+            driver: self.driver.clone(),
+            frame: driver.keyboard.last_frame(),
+            error: error,
+        }
+    }
+
+    /// Sets the color of all keys.
+    ///
+    /// The returned future resolves once the keyboard has acknowledged the colors.
+    pub fn set_all_colors(&self, color: Color) -> ColorsAcknowledged {
+        let mut driver = self.driver.borrow_mut();
+        let error = match driver.keyboard.set_all_colors(color) {
+            // the colors are restored once reconnected
+            Err(Error::Usb(_)) if driver.reconnecting => None,
+            res => res.err(),
+        };
+        ColorsAcknowledged {
+            driver: self.driver.clone(),
+            frame: driver.keyboard.last_frame(),
+            error: error,
+        }
+    }
+
+    /// Reconnects to the keyboard according to its reconnect policy, unless already reconnecting.
+    ///
+    /// The returned future resolves once the keyboard is reconnected and fails
+    /// if all attempts failed. Waiting between attempts doesn't block the reactor.
+    pub fn reconnect(&self) -> Reconnecting {
+        let mut driver = self.driver.borrow_mut();
+        if !driver.reconnecting {
+            driver.start_reconnect();
+        }
+        Reconnecting {
+            driver: self.driver.clone(),
+        }
+    }
+}
+
+impl Stream for AsyncKeyboard {
+    type Item = KeyEvent;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<KeyEvent>, Error> {
+        let mut driver = self.driver.borrow_mut();
+        match driver.events.pop_front() {
+            Some(event) => return Ok(Async::Ready(Some(event))),
+            None => {}
+        }
+        try!(driver.drive());
+        match driver.events.pop_front() {
+            Some(event) => Ok(Async::Ready(Some(event))),
+            None => {
+                driver.park();
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+/// Future resolving once the keyboard acknowledged a change of colors.
+///
+/// Resolves to `AckTimeout` if the keyboard didn't acknowledge the colors after all retries.
+pub struct ColorsAcknowledged {
+    driver: Rc<RefCell<Driver>>,
+    frame: u64,
+    // error of setting the colors, returned on the first poll
+    error: Option<Error>,
+}
+
+impl Future for ColorsAcknowledged {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        match self.error.take() {
+            Some(e) => return Err(e),
+            None => {}
+        }
+        let mut driver = self.driver.borrow_mut();
+        try!(driver.drive());
+        match driver.keyboard.frame_acknowledged(self.frame) {
+            Some(res) => res.map(Async::Ready),
+            None => {
+                driver.park();
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+/// Future resolving once the keyboard has been reconnected.
+pub struct Reconnecting {
+    driver: Rc<RefCell<Driver>>,
+}
+
+impl Future for Reconnecting {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        let mut driver = self.driver.borrow_mut();
+        if driver.reconnecting {
+            try!(driver.drive());
+        }
+        if driver.reconnecting {
+            driver.park();
+            Ok(Async::NotReady)
+        } else if driver.reconnect_failed {
+            Err(Error::Usb(UsbError::NoDevice))
+        } else {
+            Ok(Async::Ready(()))
+        }
+    }
+}
+
+fn interest(events: i16) -> Ready {
+    let mut ready = Ready::empty();
+    if events & libc::POLLIN != 0 {
+        ready = ready | Ready::readable();
+    }
+    if events & libc::POLLOUT != 0 {
+        ready = ready | Ready::writable();
+    }
+    ready
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use futures::{Future, Stream};
+    use futures::future;
+    use futures::task;
+    use tokio_core::reactor::Core;
+    use libusb::Error as UsbError;
+    use color::{Color, KeyColor};
+    use error::Error;
+    use event::KeyEvent;
+    use keys::{Key, StandardKey};
+    use fake::FakeDevice;
+    use keyboard::{Keyboard, KeyboardImpl};
+    use reconnect::ReconnectPolicy;
+    use super::AsyncKeyboard;
+
+    fn keyboard(device: &FakeDevice, core: &Core) -> AsyncKeyboard {
+        let mut keyboard = KeyboardImpl::with_transport(Box::new(device.clone()));
+        keyboard.set_reconnect_policy(ReconnectPolicy::Fixed {
+            interval: Duration::from_millis(1),
+            attempts: 3,
+        });
+        AsyncKeyboard::new(keyboard, &core.handle()).unwrap()
+    }
+
+    fn next_event(core: &mut Core, keyboard: &mut AsyncKeyboard) -> Result<Option<KeyEvent>, Error> {
+        core.run(future::poll_fn(|| keyboard.poll()))
+    }
+
+    #[test]
+    fn colors_resolve_once_acknowledged() {
+        let mut core = Core::new().unwrap();
+        let device = FakeDevice::new();
+        let keyboard = keyboard(&device, &core);
+        core.run(keyboard.set_all_colors(Color::new(255, 0, 0))).unwrap();
+        assert!(device.take_sent_control_packets().len() > 0);
+
+        // acknowledge one packet per poll
+        device.set_auto_ack(false);
+        let mut acknowledged = keyboard.set_key_colors(vec![KeyColor::new(StandardKey::A, Color::new(0, 0, 255))]);
+        let mut polls = 0;
+        core.run(future::poll_fn(|| {
+            polls += 1;
+            for packet in device.take_sent_control_packets() {
+                device.ack(&packet);
+            }
+            let res = acknowledged.poll();
+            // nothing wakes us up for the fake device's acknowledgements
+            task::current().notify();
+            res
+        })).unwrap();
+        // the color packet and the flush
+        assert_eq!(polls, 2);
+    }
+
+    #[test]
+    fn stream_yields_key_events() {
+        let mut core = Core::new().unwrap();
+        let device = FakeDevice::new();
+        let mut keyboard = keyboard(&device, &core);
+        device.emit_interrupt(0x81, vec![0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        device.emit_interrupt(0x81, vec![0x00; 8]);
+        assert_eq!(next_event(&mut core, &mut keyboard).unwrap(),
+            Some(KeyEvent::KeyPressed(Key::Standard(StandardKey::A))));
+        assert_eq!(next_event(&mut core, &mut keyboard).unwrap(),
+            Some(KeyEvent::KeyReleased(Key::Standard(StandardKey::A))));
+    }
+
+    #[test]
+    fn stream_reconnects_after_disconnect() {
+        let mut core = Core::new().unwrap();
+        let device = FakeDevice::new();
+        let mut keyboard = keyboard(&device, &core);
+        let replug = device.clone();
+        keyboard.keyboard().on_reconnecting(move |attempt| if attempt == 2 {
+            replug.set_connected(true);
+        });
+        let press = device.clone();
+        keyboard.keyboard().on_reconnected(move || {
+            press.emit_interrupt(0x81, vec![0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        });
+        device.set_connected(false);
+        assert_eq!(next_event(&mut core, &mut keyboard).unwrap(),
+            Some(KeyEvent::KeyPressed(Key::Standard(StandardKey::A))));
+        assert_eq!(device.reconnects(), 1);
+    }
+
+    #[test]
+    fn stream_fails_after_max_attempts() {
+        let mut core = Core::new().unwrap();
+        let device = FakeDevice::new();
+        let mut keyboard = keyboard(&device, &core);
+        device.set_connected(false);
+        match next_event(&mut core, &mut keyboard) {
+            Err(Error::Usb(UsbError::NoDevice)) => {},
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(device.reconnects(), 0);
+        // polling again starts reconnecting again
+        let replug = device.clone();
+        keyboard.keyboard().on_reconnecting(move |_| replug.set_connected(true));
+        let press = device.clone();
+        keyboard.keyboard().on_reconnected(move || {
+            press.emit_interrupt(0x81, vec![0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        });
+        assert_eq!(next_event(&mut core, &mut keyboard).unwrap(),
+            Some(KeyEvent::KeyPressed(Key::Standard(StandardKey::A))));
+        assert_eq!(device.reconnects(), 1);
+    }
+
+    #[test]
+    fn reconnect_future() {
+        let mut core = Core::new().unwrap();
+        let device = FakeDevice::new();
+        let keyboard = keyboard(&device, &core);
+        device.set_connected(false);
+        match core.run(keyboard.reconnect()) {
+            Err(Error::Usb(UsbError::NoDevice)) => {},
+            res => panic!("unexpected result {:?}", res),
+        }
+        let replug = device.clone();
+        keyboard.keyboard().on_reconnecting(move |_| replug.set_connected(true));
+        core.run(keyboard.reconnect()).unwrap();
+        assert_eq!(device.reconnects(), 1);
+    }
+
+    #[test]
+    fn colors_set_while_reconnecting_are_restored() {
+        let mut core = Core::new().unwrap();
+        let device = FakeDevice::new();
+        let keyboard = keyboard(&device, &core);
+        device.set_connected(false);
+        let reconnecting = keyboard.reconnect();
+        let acknowledged = keyboard.set_all_colors(Color::new(0, 255, 0));
+        assert!(device.sent_control_packets().is_empty());
+        device.set_connected(true);
+        core.run(reconnecting).unwrap();
+        core.run(acknowledged).unwrap();
+        assert!(device.sent_control_packets().len() > 0);
+    }
+}
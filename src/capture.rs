@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::os::unix::io::RawFd;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use libusb::Result as UsbResult;
+use handle::{Transport, ControlPacket};
+use model::DeviceModel;
+use replay::RecordedFrame;
+use consts;
+
+// LINKTYPE_USB_LINUX_MMAPPED, the 64 byte usbmon header
+const LINKTYPE_USB_LINUX_MMAPPED: u32 = 220;
+const USBMON_HEADER_LEN: usize = 64;
+// LINKTYPE_USB_LINUX, the 48 byte usbmon header
+const LINKTYPE_USB_LINUX: u32 = 189;
+
+const XFER_INTERRUPT: u8 = 1;
+const XFER_CONTROL: u8 = 2;
+const EINPROGRESS: i32 = -115;
+
+/// Writes usbmon packets in pcap format, which can be opened with Wireshark's USB dissector.
+pub struct PcapWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Creates a new writer, writing the pcap file header.
+    pub fn new(mut writer: W) -> io::Result<PcapWriter<W>> {
+        // magic, in host byte order like the usbmon header
+        try!(writer.write_u32::<LittleEndian>(0xa1b2c3d4));
+        // version 2.4
+        try!(writer.write_u16::<LittleEndian>(2));
+        try!(writer.write_u16::<LittleEndian>(4));
+        // timezone offset and timestamp accuracy
+        try!(writer.write_i32::<LittleEndian>(0));
+        try!(writer.write_u32::<LittleEndian>(0));
+        // snaplen
+        try!(writer.write_u32::<LittleEndian>(65535));
+        try!(writer.write_u32::<LittleEndian>(LINKTYPE_USB_LINUX_MMAPPED));
+        Ok(PcapWriter {
+            writer: writer,
+        })
+    }
+
+    pub fn write(&mut self, record: &UsbmonRecord) -> io::Result<()> {
+        let len = (USBMON_HEADER_LEN + record.data.len()) as u32;
+        let timestamp = record.timestamp.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+        let ts_sec = timestamp.as_secs();
+        let ts_usec = timestamp.subsec_nanos() / 1000;
+        // pcap record header
+        try!(self.writer.write_u32::<LittleEndian>(ts_sec as u32));
+        try!(self.writer.write_u32::<LittleEndian>(ts_usec));
+        try!(self.writer.write_u32::<LittleEndian>(len));
+        try!(self.writer.write_u32::<LittleEndian>(len));
+        // usbmon header, see Documentation/usb/usbmon.txt
+        try!(self.writer.write_u64::<LittleEndian>(record.id));
+        try!(self.writer.write_u8(if record.submit { b'S' } else { b'C' }));
+        try!(self.writer.write_u8(if record.control { XFER_CONTROL } else { XFER_INTERRUPT }));
+        try!(self.writer.write_u8(record.endpoint));
+        try!(self.writer.write_u8(record.device_address));
+        try!(self.writer.write_u16::<LittleEndian>(record.bus_number as u16));
+        // flag_setup: 0 if the setup packet is valid
+        try!(self.writer.write_u8(if record.setup.is_some() { 0 } else { b'-' }));
+        // flag_data: 0 if data is present, '<' for submitted IN transfers
+        try!(self.writer.write_u8(if record.data.len() > 0 { 0 } else if record.submit { b'<' } else { b'>' }));
+        try!(self.writer.write_i64::<LittleEndian>(ts_sec as i64));
+        try!(self.writer.write_i32::<LittleEndian>(ts_usec as i32));
+        try!(self.writer.write_i32::<LittleEndian>(if record.submit { EINPROGRESS } else { 0 }));
+        try!(self.writer.write_u32::<LittleEndian>(record.length));
+        try!(self.writer.write_u32::<LittleEndian>(record.data.len() as u32));
+        try!(self.writer.write_all(&record.setup.unwrap_or([0; 8])));
+        // interval, start_frame, xfer_flags, ndesc
+        try!(self.writer.write_i32::<LittleEndian>(0));
+        try!(self.writer.write_i32::<LittleEndian>(0));
+        try!(self.writer.write_u32::<LittleEndian>(0));
+        try!(self.writer.write_u32::<LittleEndian>(0));
+        try!(self.writer.write_all(&record.data));
+        self.writer.flush()
+    }
+}
+
+/// A single usbmon event: submission or completion of a transfer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsbmonRecord {
+    /// Identifies the transfer, the same for submission and completion.
+    pub id: u64,
+    pub submit: bool,
+    /// Whether this is a control transfer, otherwise it is an interrupt transfer.
+    pub control: bool,
+    /// Endpoint number including the direction bit.
+    pub endpoint: u8,
+    pub bus_number: u8,
+    pub device_address: u8,
+    /// Setup packet of submitted control transfers.
+    pub setup: Option<[u8; 8]>,
+    /// Requested length of the transfer.
+    pub length: u32,
+    pub data: Vec<u8>,
+    pub timestamp: SystemTime,
+}
+
+/// Transport recording all traffic of another transport in pcap format.
+///
+/// Bus number and address are taken from the wrapped transport, see `Transport::bus_address`.
+/// Of the transfers submitted before wrapping the transport, only the ones listening
+/// for reports are recorded, see `Transport::listening`.
+pub struct CaptureTransport<W: Write> {
+    inner: Box<Transport>,
+    // None after a write error, which stops capturing
+    writer: Option<PcapWriter<W>>,
+    next_id: u64,
+    // per endpoint ids of transfers which have been submitted but not yet completed
+    submitted: HashMap<u8, Vec<(u64, u32)>>,
+}
+
+impl<W: Write> CaptureTransport<W> {
+    pub fn new(inner: Box<Transport>, writer: W) -> io::Result<CaptureTransport<W>> {
+        let mut capture = CaptureTransport {
+            inner: inner,
+            writer: Some(try!(PcapWriter::new(writer))),
+            next_id: 0,
+            submitted: HashMap::new(),
+        };
+        capture.submit_listening();
+        Ok(capture)
+    }
+
+    fn record(&mut self, id: u64, submit: bool, control: bool, endpoint: u8,
+              setup: Option<[u8; 8]>, length: u32, data: Vec<u8>) {
+        let (bus_number, device_address) = self.inner.bus_address().unwrap_or((0, 0));
+        let record = UsbmonRecord {
+            id: id,
+            submit: submit,
+            control: control,
+            endpoint: endpoint,
+            bus_number: bus_number,
+            device_address: device_address,
+            setup: setup,
+            length: length,
+            data: data,
+            timestamp: SystemTime::now(),
+        };
+        let res = match self.writer {
+            Some(ref mut writer) => writer.write(&record),
+            None => return,
+        };
+        match res {
+            Ok(()) => {},
+            Err(e) => {
+                error!(target: consts::LOG_TRANSPORT, "Error writing capture, stopping capture: {}", e);
+                self.writer = None;
+            }
+        }
+    }
+
+    fn submit(&mut self, endpoint: u8, setup: Option<[u8; 8]>, length: u32, data: Vec<u8>) {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.submitted.entry(endpoint).or_insert(Vec::new()).push((id, length));
+        let control = setup.is_some();
+        self.record(id, true, control, endpoint, setup, length, data);
+    }
+
+    // records the transfers the wrapped transport submitted by itself while connecting
+    fn submit_listening(&mut self) {
+        for (endpoint, length) in self.inner.listening() {
+            self.submit(endpoint, None, length as u32, Vec::new());
+        }
+    }
+}
+
+fn setup_packet(packet: &ControlPacket) -> [u8; 8] {
+    let mut setup = Vec::with_capacity(8);
+    setup.write_u8(packet.request_type()).unwrap();
+    setup.write_u8(packet.request()).unwrap();
+    setup.write_u16::<LittleEndian>(packet.value()).unwrap();
+    setup.write_u16::<LittleEndian>(packet.index()).unwrap();
+    setup.write_u16::<LittleEndian>(packet.buf().len() as u16).unwrap();
+    let mut res = [0; 8];
+    res.copy_from_slice(&setup);
+    res
+}
+
+fn control_endpoint(request_type: u8) -> u8 {
+    // control transfers use endpoint 0, the direction is given by the request type
+    request_type & 0x80
+}
+
+impl<W: Write> Transport for CaptureTransport<W> {
+    fn send_control(&mut self, packet: ControlPacket) -> UsbResult<()> {
+        let endpoint = control_endpoint(packet.request_type());
+        let setup = setup_packet(&packet);
+        let length = packet.buf().len() as u32;
+        // IN transfers don't have data yet
+        let data = if endpoint == 0 { packet.buf().to_vec() } else { Vec::new() };
+        self.submit(endpoint, Some(setup), length, data);
+        self.inner.send_control(packet)
+    }
+
+    fn send_interrupt(&mut self, endpoint_direction: u8, buf: Vec<u8>, timeout: Duration) -> UsbResult<()> {
+        let data = if endpoint_direction & 0x80 == 0 { buf.clone() } else { Vec::new() };
+        self.submit(endpoint_direction, None, buf.len() as u32, data);
+        self.inner.send_interrupt(endpoint_direction, buf, timeout)
+    }
+
+    fn recv(&mut self, timeout: Duration) -> Option<UsbResult<(u8, Vec<u8>)>> {
+        let res = self.inner.recv(timeout);
+        let (endpoint_direction, buf) = match res {
+            Some(Ok((e, ref b))) => (e, b.clone()),
+            _ => return res,
+        };
+        let control = endpoint_direction & 0x7f == 0;
+        // completed control transfers are reported with their buffer on 0x80
+        // by the transports, even if they were OUT transfers
+        let endpoint = if control {
+            if self.submitted.get(&0x00).map_or(false, |ids| ids.len() > 0) { 0x00 } else { 0x80 }
+        } else {
+            endpoint_direction
+        };
+        let (id, length) = match self.submitted.get_mut(&endpoint) {
+            Some(ref mut ids) if ids.len() > 0 => ids.remove(0),
+            _ => {
+                let id = self.next_id;
+                self.next_id += 1;
+                (id, buf.len() as u32)
+            }
+        };
+        // OUT control transfers don't return data
+        let data = if control && endpoint == 0x00 { Vec::new() } else { buf };
+        self.record(id, false, control, endpoint, None, length, data);
+        // interrupt transfers are resubmitted by Handle
+        if !control {
+            self.submit(endpoint, None, length, Vec::new());
+        }
+        res
+    }
+
+    fn reconnect(&mut self) -> UsbResult<()> {
+        // transfers of the old connection will never complete
+        self.submitted.clear();
+        try!(self.inner.reconnect());
+        self.submit_listening();
+        Ok(())
+    }
+
+    fn wait_for_device(&mut self, timeout: Option<Duration>) -> Option<UsbResult<bool>> {
+        self.inner.wait_for_device(timeout)
+    }
+
+    fn pollfds(&self) -> Vec<(RawFd, i16)> {
+        self.inner.pollfds()
+    }
+
+    fn bus_address(&self) -> Option<(u8, u8)> {
+        self.inner.bus_address()
+    }
+
+    fn listening(&self) -> Vec<(u8, usize)> {
+        self.inner.listening()
+    }
+
+    fn model(&self) -> &'static DeviceModel {
+        self.inner.model()
+    }
+}
+
+/// Reads all completed IN transfers of a usbmon pcap capture as frames for `Replay`.
+///
+/// Only classic little-endian pcap files with microsecond timestamps are supported, as written
+/// by `CaptureTransport` and by tcpdump on a usbmon interface on x86 and ARM.
+/// Wireshark writes pcapng by default, so save its captures as "Wireshark/tcpdump - pcap" first.
+pub fn read_pcap<R: Read>(mut reader: R) -> io::Result<Vec<RecordedFrame>> {
+    let magic = try!(reader.read_u32::<LittleEndian>());
+    if magic != 0xa1b2c3d4 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "unsupported pcap byte order or timestamp format"));
+    }
+    // version, timezone offset, timestamp accuracy, snaplen
+    let mut skip = [0; 16];
+    try!(reader.read_exact(&mut skip));
+    let header_len = match try!(reader.read_u32::<LittleEndian>()) {
+        LINKTYPE_USB_LINUX_MMAPPED => USBMON_HEADER_LEN,
+        LINKTYPE_USB_LINUX => 48,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "not a usbmon capture")),
+    };
+    let mut frames = Vec::new();
+    let mut start = None;
+    loop {
+        let ts_sec = match reader.read_u32::<LittleEndian>() {
+            Ok(ts_sec) => ts_sec,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+        let ts_usec = try!(reader.read_u32::<LittleEndian>());
+        let len = try!(reader.read_u32::<LittleEndian>()) as usize;
+        let _orig_len = try!(reader.read_u32::<LittleEndian>());
+        let mut packet = vec![0; len];
+        try!(reader.read_exact(&mut packet));
+        if packet.len() < header_len {
+            continue;
+        }
+        let timestamp = Duration::new(ts_sec as u64, ts_usec * 1000);
+        let start = *start.get_or_insert(timestamp);
+        let completed = packet[8] == b'C';
+        let endpoint = packet[10];
+        let len_cap = LittleEndian::read_u32(&packet[36..40]) as usize;
+        // only transfers from the keyboard are replayed
+        if !completed || endpoint & 0x80 == 0 || len_cap == 0 {
+            continue;
+        }
+        let end = ::std::cmp::min(header_len + len_cap, packet.len());
+        let buf = packet[header_len..end].to_vec();
+        frames.push(RecordedFrame::new(endpoint, buf, timestamp.checked_sub(start).unwrap_or(Duration::from_secs(0))));
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use byteorder::{ByteOrder, LittleEndian};
+    use handle::Transport;
+    use fake::FakeDevice;
+    use super::CaptureTransport;
+
+    // id, event type, endpoint, bus and address of each record
+    fn records(mut buf: &[u8]) -> Vec<(u64, u8, u8, u16, u8)> {
+        let mut res = Vec::new();
+        // file header
+        buf = &buf[24..];
+        while buf.len() > 0 {
+            let len = LittleEndian::read_u32(&buf[8..12]) as usize;
+            let usbmon = &buf[16..16 + len];
+            res.push((LittleEndian::read_u64(&usbmon[0..8]), usbmon[8], usbmon[10],
+                LittleEndian::read_u16(&usbmon[12..14]), usbmon[11]));
+            buf = &buf[16 + len..];
+        }
+        res
+    }
+
+    #[test]
+    fn listening_transfers_are_recorded_after_reconnect() {
+        let device = FakeDevice::new();
+        let mut buf = Vec::new();
+        {
+            let mut capture = CaptureTransport::new(Box::new(device.clone()), &mut buf).unwrap();
+            device.emit_interrupt(0x81, vec![0x00; 8]);
+            capture.recv(Duration::from_secs(0)).unwrap().unwrap();
+            capture.reconnect().unwrap();
+            device.emit_interrupt(0x82, vec![0x11; 20]);
+            capture.recv(Duration::from_secs(0)).unwrap().unwrap();
+        }
+        assert_eq!(records(&buf), vec![
+            (0, b'S', 0x81, 1, 1),
+            (1, b'S', 0x82, 1, 1),
+            (0, b'C', 0x81, 1, 1),
+            (2, b'S', 0x81, 1, 1),
+            // the replugged keyboard has a new address
+            (3, b'S', 0x81, 1, 2),
+            (4, b'S', 0x82, 1, 2),
+            (4, b'C', 0x82, 1, 2),
+            (5, b'S', 0x82, 1, 2),
+        ]);
+    }
+}
@@ -1,6 +1,7 @@
 use std::time::Duration;
 use keys::*;
 use handle::{ToControlPacket, ControlPacket};
+use model::DeviceModel;
 use byteorder::{BigEndian, WriteBytesExt};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -18,6 +19,33 @@ impl Color {
             blue: blue,
         }
     }
+
+    /// Returns the fully saturated color of given hue, where 0 and 1 are red.
+    pub fn from_hue(hue: f32) -> Color {
+        let hue = (hue - hue.floor()) * 6.0;
+        let x = ((1.0 - (hue % 2.0 - 1.0).abs()) * 255.0).round() as u8;
+        match hue as u8 {
+            0 => Color::new(255, x, 0),
+            1 => Color::new(x, 255, 0),
+            2 => Color::new(0, 255, x),
+            3 => Color::new(0, x, 255),
+            4 => Color::new(x, 0, 255),
+            _ => Color::new(255, 0, x),
+        }
+    }
+
+    /// Linearly interpolates between this color at 0 and the other one at 1.
+    pub fn mix(&self, other: &Color, t: f32) -> Color {
+        let t = t.max(0.0).min(1.0);
+        let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+        Color::new(mix(self.red, other.red), mix(self.green, other.green), mix(self.blue, other.blue))
+    }
+
+    /// Returns this color dimmed to given brightness in percent.
+    pub fn scale(&self, percent: u8) -> Color {
+        let scale = |c: u8| (c as u32 * percent.min(100) as u32 / 100) as u8;
+        Color::new(scale(self.red), scale(self.green), scale(self.blue))
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -37,12 +65,14 @@ impl KeyColor {
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ColorPacket<T: KeyType> {
+    model: &'static DeviceModel,
     colors: Vec<(T, Color)>,
 }
 
 impl<T: KeyType> ColorPacket<T> {
-    pub fn new() -> ColorPacket<T> {
+    pub fn new(model: &'static DeviceModel) -> ColorPacket<T> {
         ColorPacket {
+            model: model,
             colors: Vec::new(),
         }
     }
@@ -56,9 +86,9 @@ impl<T: KeyType> ColorPacket<T> {
     ///
     /// Otherwise None will be returned and more colors can be added to this instance.
     pub fn add(&mut self, key: T, color: Color) -> Option<ColorPacket<T>> {
-        assert!(self.colors.len() <= 14);
-        let res = if self.colors.len() == 14 {
-            Some(::std::mem::replace(self, ColorPacket::new()))
+        assert!(self.colors.len() <= self.model.keys_per_packet);
+        let res = if self.colors.len() == self.model.keys_per_packet {
+            Some(::std::mem::replace(self, ColorPacket::new(self.model)))
         } else {
             None
         };
@@ -76,7 +106,7 @@ impl<T: KeyType> ToControlPacket for ColorPacket<T> {
     fn to_control_packet(mut self) -> ControlPacket {
         let mut buf = Vec::new();
         // head
-        buf.write_u32::<BigEndian>(0x12ff0f3b).unwrap();
+        buf.write_u32::<BigEndian>(self.model.color_header).unwrap();
         // key type
         // if none is specified, no data exists and no key will be set
         // as From can not return a Result, just use any key type
@@ -99,11 +129,15 @@ impl<T: KeyType> ToControlPacket for ColorPacket<T> {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct FlushPacket;
+pub struct FlushPacket {
+    model: &'static DeviceModel,
+}
 
 impl FlushPacket {
-    pub fn new() -> FlushPacket {
-        FlushPacket {  }
+    pub fn new(model: &'static DeviceModel) -> FlushPacket {
+        FlushPacket {
+            model: model,
+        }
     }
 }
 
@@ -111,10 +145,55 @@ impl ToControlPacket for FlushPacket {
     fn to_control_packet(self) -> ControlPacket {
         let mut buf = Vec::new();
         // head
-        buf.write_u32::<BigEndian>(0x11ff0f5b).unwrap();
+        buf.write_u32::<BigEndian>(self.model.flush_header).unwrap();
         // body is 0
         buf.resize(20, 0u8);
         ControlPacket::new(buf, 0x80, 0x21, 9, 0x0212, 0x0001, Duration::from_secs(10))
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::Color;
+
+    #[test]
+    fn from_hue() {
+        let tests = [
+            (0.0, Color::new(255, 0, 0)),
+            (1.0 / 6.0, Color::new(255, 255, 0)),
+            (1.0 / 3.0, Color::new(0, 255, 0)),
+            (0.5, Color::new(0, 255, 255)),
+            (2.0 / 3.0, Color::new(0, 0, 255)),
+            (5.0 / 6.0, Color::new(255, 0, 255)),
+            // hues wrap around
+            (1.0, Color::new(255, 0, 0)),
+            (-1.0 / 3.0, Color::new(0, 0, 255)),
+        ];
+        for &(hue, color) in tests.iter() {
+            assert_eq!(Color::from_hue(hue), color, "hue {}", hue);
+        }
+        assert_eq!(Color::from_hue(1.0 / 12.0), Color::new(255, 128, 0));
+    }
+
+    #[test]
+    fn mix() {
+        let black = Color::new(0, 0, 0);
+        let color = Color::new(255, 100, 10);
+        assert_eq!(black.mix(&color, 0.0), black);
+        assert_eq!(black.mix(&color, 0.5), Color::new(128, 50, 5));
+        assert_eq!(black.mix(&color, 1.0), color);
+        assert_eq!(color.mix(&black, 0.5), Color::new(128, 50, 5));
+        // t is clamped
+        assert_eq!(black.mix(&color, -1.0), black);
+        assert_eq!(black.mix(&color, 2.0), color);
+    }
+
+    #[test]
+    fn scale() {
+        let color = Color::new(255, 100, 10);
+        assert_eq!(color.scale(100), color);
+        assert_eq!(color.scale(50), Color::new(127, 50, 5));
+        assert_eq!(color.scale(0), Color::new(0, 0, 0));
+        assert_eq!(color.scale(200), color);
+    }
+}
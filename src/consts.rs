@@ -1,2 +1,8 @@
 pub const VENDOR_ID: u16 = 0x046d;
 pub const PRODUCT_ID: u16 = 0xc32b;
+
+// log targets
+pub const LOG_TRANSPORT: &'static str = "g910::transport";
+pub const LOG_PARSER: &'static str = "g910::parser";
+pub const LOG_RECONNECT: &'static str = "g910::reconnect";
+pub const LOG_REMOTE: &'static str = "g910::remote";
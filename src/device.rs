@@ -0,0 +1,118 @@
+use libusb::Result as UsbResult;
+use utils;
+use consts;
+use model::{self, DeviceModel};
+
+/// Describes a connected keyboard.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub product_id: u16,
+    pub bus_number: u8,
+    pub address: u8,
+    /// Port numbers from the root hub to the device, see `libusb_get_port_numbers`.
+    pub port_numbers: Vec<u8>,
+    /// Serial number string, if the device has one and it could be read.
+    pub serial: Option<String>,
+}
+
+impl DeviceInfo {
+    /// Returns the model of the keyboard, None if its product id isn't supported.
+    pub fn model(&self) -> Option<&'static DeviceModel> {
+        model::find(consts::VENDOR_ID, self.product_id)
+    }
+
+    /// Returns a selector which finds this physical keyboard again, even after it has been replugged.
+    ///
+    /// As the address changes on every replug, the serial number is used if available.
+    /// Otherwise the port path is used, which is stable as long as the keyboard is
+    /// plugged into the same port.
+    pub fn selector(&self) -> DeviceSelector {
+        match self.serial {
+            Some(ref serial) => DeviceSelector::Serial(serial.clone()),
+            None if self.port_numbers.len() > 0 =>
+                DeviceSelector::Port(self.bus_number, self.port_numbers.clone()),
+            None => DeviceSelector::Any,
+        }
+    }
+}
+
+/// Selects which keyboard to open if multiple are connected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceSelector {
+    /// The first keyboard found.
+    Any,
+    /// Bus number and address.
+    BusAddress(u8, u8),
+    /// Bus number and port path.
+    Port(u8, Vec<u8>),
+    /// Serial number string.
+    Serial(String),
+}
+
+impl DeviceSelector {
+    pub fn matches(&self, info: &DeviceInfo) -> bool {
+        match self {
+            &DeviceSelector::Any => true,
+            &DeviceSelector::BusAddress(bus, address) =>
+                info.bus_number == bus && info.address == address,
+            &DeviceSelector::Port(bus, ref ports) =>
+                info.bus_number == bus && &info.port_numbers == ports,
+            &DeviceSelector::Serial(ref serial) =>
+                info.serial.as_ref() == Some(serial),
+        }
+    }
+}
+
+/// Determines which interfaces of the keyboard are claimed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionMode {
+    /// Claims both the typing interface (0) and the HID++ interface (1).
+    /// While connected, the keyboard doesn't type into the OS.
+    Full,
+    /// Only claims the HID++ interface (1) needed for lighting.
+    /// The typing interface stays bound to the kernel driver, but
+    /// no key events are received.
+    ///
+    /// The kernel driver is detached from interface 1 while it is claimed. That interface
+    /// also reports the media keys, so they don't work in the OS until the keyboard is dropped.
+    LightingOnly,
+}
+
+/// Lists all connected keyboards.
+pub fn devices() -> UsbResult<Vec<DeviceInfo>> {
+    let context = try!(utils::get_context());
+    utils::list_devices(&context)
+}
+
+#[cfg(test)]
+mod tests {
+    use model;
+    use super::{DeviceInfo, DeviceSelector};
+
+    fn info(product_id: u16, serial: Option<&str>) -> DeviceInfo {
+        DeviceInfo {
+            product_id: product_id,
+            bus_number: 1,
+            address: 5,
+            port_numbers: vec![2, 1],
+            serial: serial.map(|s| s.to_string()),
+        }
+    }
+
+    #[test]
+    fn model() {
+        assert_eq!(info(0xc32b, None).model(), Some(&model::G910));
+        assert_eq!(info(0xc331, None).model(), Some(&model::G810));
+        assert_eq!(info(0x1234, None).model(), None);
+    }
+
+    #[test]
+    fn selector() {
+        assert_eq!(info(0xc32b, Some("abc")).selector(), DeviceSelector::Serial("abc".to_string()));
+        assert_eq!(info(0xc32b, None).selector(), DeviceSelector::Port(1, vec![2, 1]));
+        let info = info(0xc32b, None);
+        assert!(DeviceSelector::BusAddress(1, 5).matches(&info));
+        assert!(!DeviceSelector::BusAddress(1, 6).matches(&info));
+        assert!(!DeviceSelector::Serial("abc".to_string()).matches(&info));
+    }
+}
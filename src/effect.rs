@@ -0,0 +1,237 @@
+use std::f32::consts::PI;
+use std::time::{Duration, Instant};
+use color::{Color, KeyColor};
+use event::{Handler, HandlerBuilder};
+use geometry::{Geometry, Layout};
+use keyboard::Keyboard;
+use native_effect::WaveDirection;
+use error::Result;
+
+/// Animation computed on the host, rendering the color of every key for each frame.
+pub trait Effect {
+    /// Returns the colors of given frame, `elapsed` is the time since the effect started.
+    ///
+    /// Keys which are left out keep their color.
+    fn render(&mut self, elapsed: Duration, geometry: &Geometry) -> Vec<KeyColor>;
+}
+
+/// Drives an effect from the handle loop at a target frame rate.
+///
+/// Only keys whose color changed are sent. If the keyboard can't keep up with the frame rate,
+/// frames are merged, so frames are skipped but the effect doesn't lag behind.
+pub struct EffectRunner {
+    effect: Box<Effect>,
+    layout: Layout,
+    geometry: Option<Geometry>,
+    started: Option<Instant>,
+}
+
+impl EffectRunner {
+    pub fn new<E: 'static + Effect>(effect: E, layout: Layout) -> EffectRunner {
+        EffectRunner {
+            effect: Box::new(effect),
+            layout: layout,
+            geometry: None,
+            started: None,
+        }
+    }
+
+    /// Returns a handler rendering a frame `fps` times per second, starting once it's added.
+    pub fn handler(self, fps: u32) -> Handler {
+        let interval = Duration::new(0, 1_000_000_000 / fps.max(1));
+        HandlerBuilder::new(self)
+            .handle_time_fn(|runner, _, keyboard| runner.render(keyboard), interval)
+            .build()
+    }
+
+    fn render(&mut self, keyboard: &mut Keyboard) -> Result<()> {
+        let &mut EffectRunner {
+            ref mut effect,
+            ref mut geometry,
+            ref mut started,
+            layout,
+        } = self;
+        // only render keys the model can light
+        let geometry = geometry.get_or_insert_with(|| Geometry::for_model(keyboard.model(), layout));
+        let elapsed = started.get_or_insert_with(Instant::now).elapsed();
+        let key_colors = effect.render(elapsed, geometry);
+        keyboard.set_key_colors(key_colors)
+    }
+}
+
+/// Rainbow moving across the keyboard.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RainbowWave {
+    pub direction: WaveDirection,
+    /// Time the rainbow needs to move by one wavelength.
+    pub period: Duration,
+    /// Length of the full rainbow in key units.
+    pub wavelength: f32,
+}
+
+impl Effect for RainbowWave {
+    fn render(&mut self, elapsed: Duration, geometry: &Geometry) -> Vec<KeyColor> {
+        let phase = phase(elapsed, self.period);
+        geometry.keys().iter()
+            .map(|&(ref key, ref rect)| {
+                let position = position(self.direction, geometry, rect.center());
+                KeyColor::new(key.clone(), Color::from_hue(position / self.wavelength - phase))
+            })
+            .collect()
+    }
+}
+
+/// All keys fading in and out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Breathing {
+    pub color: Color,
+    pub period: Duration,
+}
+
+impl Effect for Breathing {
+    fn render(&mut self, elapsed: Duration, geometry: &Geometry) -> Vec<KeyColor> {
+        let brightness = (1.0 - (phase(elapsed, self.period) * 2.0 * PI).cos()) / 2.0;
+        let color = Color::new(0, 0, 0).mix(&self.color, brightness);
+        geometry.keys().iter()
+            .map(|&(ref key, _)| KeyColor::new(key.clone(), color))
+            .collect()
+    }
+}
+
+/// All keys cycling through all hues.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColorCycle {
+    pub period: Duration,
+}
+
+impl Effect for ColorCycle {
+    fn render(&mut self, elapsed: Duration, geometry: &Geometry) -> Vec<KeyColor> {
+        let color = Color::from_hue(phase(elapsed, self.period));
+        geometry.keys().iter()
+            .map(|&(ref key, _)| KeyColor::new(key.clone(), color))
+            .collect()
+    }
+}
+
+/// Gradient from one color to the other and back, moving across the keyboard.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GradientSweep {
+    pub from: Color,
+    pub to: Color,
+    pub direction: WaveDirection,
+    /// Time the gradient needs to move across the keyboard.
+    pub period: Duration,
+}
+
+impl Effect for GradientSweep {
+    fn render(&mut self, elapsed: Duration, geometry: &Geometry) -> Vec<KeyColor> {
+        let phase = phase(elapsed, self.period);
+        let (width, height) = geometry.size();
+        let length = match self.direction {
+            WaveDirection::Horizontal => width,
+            WaveDirection::Vertical => height,
+            WaveDirection::CenterOut => (width * width + height * height).sqrt() / 2.0,
+        };
+        geometry.keys().iter()
+            .map(|&(ref key, ref rect)| {
+                let position = position(self.direction, geometry, rect.center()) / length - phase;
+                // triangle wave, so the gradient wraps around without a hard edge
+                let t = 1.0 - ((position - position.floor()) * 2.0 - 1.0).abs();
+                KeyColor::new(key.clone(), self.from.mix(&self.to, t))
+            })
+            .collect()
+    }
+}
+
+// fraction of the current period, between 0 and 1
+fn phase(elapsed: Duration, period: Duration) -> f32 {
+    let period = secs(period);
+    if period == 0.0 {
+        return 0.0;
+    }
+    let phase = secs(elapsed) / period;
+    phase - phase.floor()
+}
+
+fn secs(duration: Duration) -> f32 {
+    duration.as_secs() as f32 + duration.subsec_nanos() as f32 / 1_000_000_000.0
+}
+
+// distance along the direction of a wave in key units
+fn position(direction: WaveDirection, geometry: &Geometry, (x, y): (f32, f32)) -> f32 {
+    match direction {
+        WaveDirection::Horizontal => x,
+        WaveDirection::Vertical => y,
+        WaveDirection::CenterOut => {
+            let (width, height) = geometry.size();
+            let (dx, dy) = (x - width / 2.0, y - height / 2.0);
+            (dx * dx + dy * dy).sqrt()
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use color::{Color, ColorPacket, FlushPacket, KeyColor};
+    use fake::FakeDevice;
+    use geometry::{Geometry, Layout};
+    use handle::ToControlPacket;
+    use keyboard::{Keyboard, KeyboardImpl};
+    use keys::{Key, StandardKey};
+    use model;
+    use super::{ColorCycle, Effect, EffectRunner, phase};
+
+    #[test]
+    fn phases() {
+        let period = Duration::from_secs(2);
+        assert_eq!(phase(Duration::from_secs(0), period), 0.0);
+        assert_eq!(phase(Duration::from_millis(500), period), 0.25);
+        assert_eq!(phase(Duration::from_secs(3), period), 0.5);
+        assert_eq!(phase(Duration::from_secs(4), period), 0.0);
+        // a zero period stands still instead of dividing by zero
+        assert_eq!(phase(Duration::from_secs(3), Duration::from_secs(0)), 0.0);
+        let geometry = Geometry::new(Layout::Iso);
+        let mut cycle = ColorCycle {
+            period: Duration::from_secs(0),
+        };
+        let colors = cycle.render(Duration::from_secs(1), &geometry);
+        assert_eq!(colors.len(), geometry.keys().len());
+        assert!(colors.iter().all(|c| c.color == Color::new(255, 0, 0)));
+    }
+
+    // A stays red, B turns from red to blue after the first frame
+    struct Switch {
+        frames: u32,
+    }
+
+    impl Effect for Switch {
+        fn render(&mut self, _elapsed: Duration, _geometry: &Geometry) -> Vec<KeyColor> {
+            let b = if self.frames == 0 { Color::new(255, 0, 0) } else { Color::new(0, 0, 255) };
+            self.frames += 1;
+            vec![KeyColor::new(StandardKey::A, Color::new(255, 0, 0)), KeyColor::new(StandardKey::B, b)]
+        }
+    }
+
+    #[test]
+    fn runner_sends_changed_keys() {
+        let device = FakeDevice::new();
+        let mut keyboard = KeyboardImpl::with_transport(Box::new(device.clone()));
+        let mut runner = EffectRunner::new(Switch { frames: 0 }, Layout::Iso);
+        let mut frame = |keyboard: &mut KeyboardImpl| {
+            runner.render(keyboard).unwrap();
+            while keyboard.poll_events().unwrap().is_some() {}
+            device.take_sent_control_packets()
+        };
+        let flush = FlushPacket::new(&model::G910).to_control_packet();
+        let mut first = ColorPacket::new(&model::G910);
+        first.add(StandardKey::A, Color::new(255, 0, 0));
+        first.add(StandardKey::B, Color::new(255, 0, 0));
+        assert_eq!(frame(&mut keyboard), vec![first.to_control_packet(), flush.clone()]);
+        let mut second = ColorPacket::new(&model::G910);
+        second.add(StandardKey::B, Color::new(0, 0, 255));
+        assert_eq!(frame(&mut keyboard), vec![second.to_control_packet(), flush]);
+        assert!(frame(&mut keyboard).is_empty());
+        assert_eq!(keyboard.get_color(&Key::Standard(StandardKey::B)), Some(Color::new(0, 0, 255)));
+    }
+}
@@ -0,0 +1,77 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::result::Result as StdResult;
+use libusb::Error as UsbError;
+use keys::Key;
+use native_effect::Zone;
+use utils::Hex;
+
+pub type Result<T> = StdResult<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Error of the underlying USB transport.
+    Usb(UsbError),
+    /// The key can't be lit, either because it's a media key or the model doesn't have it.
+    UnsupportedKey(Key),
+    /// The model doesn't have given lighting zone.
+    UnsupportedZone(Zone),
+    /// A key selection couldn't be parsed, see `parse_keys`.
+    InvalidKeySelection(String),
+    /// A packet received from the keyboard is malformed or unknown.
+    MalformedPacket(Vec<u8>),
+    /// The keyboard didn't acknowledge a control packet, even after resending it.
+    AckTimeout,
+    /// No keyboard with any of the product ids is connected.
+    DeviceNotFound {
+        vendor_id: u16,
+        product_ids: Vec<u16>,
+    },
+    /// I/O error outside of libusb, e.g. of uinput.
+    Io(io::Error),
+    /// The keyboard controlled by a `Remote` has been dropped.
+    RemoteClosed,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Usb(ref e) => write!(f, "USB error: {}", e),
+            Error::UnsupportedKey(ref key) => write!(f, "key can't be lit: {:?}", key),
+            Error::UnsupportedZone(zone) => write!(f, "model doesn't have lighting zone {:?}", zone),
+            Error::InvalidKeySelection(ref reason) => write!(f, "invalid key selection: {}", reason),
+            Error::MalformedPacket(ref buf) => write!(f, "malformed or unknown packet: {}", Hex(buf)),
+            Error::AckTimeout => write!(f, "keyboard didn't acknowledge control packet"),
+            Error::DeviceNotFound { vendor_id, ref product_ids } => {
+                let product_ids: Vec<_> = product_ids.iter().map(|id| format!("{:04x}", id)).collect();
+                write!(f, "no keyboard found with vendor id {:04x} and product id {}",
+                       vendor_id, product_ids.join(", "))
+            },
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::RemoteClosed => write!(f, "keyboard of remote has been dropped"),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(StdError + 'static)> {
+        match *self {
+            Error::Usb(ref e) => Some(e),
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<UsbError> for Error {
+    fn from(err: UsbError) -> Error {
+        Error::Usb(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
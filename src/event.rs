@@ -1,7 +1,7 @@
 use std::time::{Duration, SystemTime};
 use keys::Key;
 use keyboard::Keyboard;
-use libusb::Result as UsbResult;
+use error::Result;
 
 pub struct Handler(Box<GenericHandler>);
 
@@ -12,20 +12,23 @@ impl From<Handler> for Box<GenericHandler> {
 }
 
 pub trait GenericHandler {
-    fn init(&mut self, &mut Keyboard) -> UsbResult<()>;
+    fn init(&mut self, &mut Keyboard) -> Result<()>;
     fn accept_key(&self, &KeyEvent) -> bool;
-    fn handle_key(&mut self, &KeyEvent, &mut Keyboard) -> UsbResult<()>;
-    fn handle_time(&mut self, &mut Keyboard) -> UsbResult<()>;
+    /// Whether an accepted key event should not be passed through to the OS.
+    fn swallow_key(&self, &KeyEvent) -> bool;
+    fn handle_key(&mut self, &KeyEvent, &mut Keyboard) -> Result<()>;
+    fn handle_time(&mut self, &mut Keyboard) -> Result<()>;
     fn sleep_duration(&self) -> Option<Duration>;
 }
 
 pub struct HandlerBuilder<T: Sized> {
     user_data: T,
-    init_fn: Option<Box<Fn(&mut T, &mut Keyboard) -> UsbResult<()>>>,
+    init_fn: Option<Box<Fn(&mut T, &mut Keyboard) -> Result<()>>>,
     accept_key_fn: Option<Box<Fn(&T, &KeyEvent) -> bool>>,
-    handle_key_fn: Option<Box<Fn(&mut T, &KeyEvent, &mut Keyboard) -> UsbResult<()>>>,
+    swallow_key_fn: Option<Box<Fn(&T, &KeyEvent) -> bool>>,
+    handle_key_fn: Option<Box<Fn(&mut T, &KeyEvent, &mut Keyboard) -> Result<()>>>,
     // (handle_time function, sleep_time, last_called)
-    handle_time_fn: Option<(Box<Fn(&mut T, Duration, &mut Keyboard) -> UsbResult<()>>, Duration, SystemTime)>,
+    handle_time_fn: Option<(Box<Fn(&mut T, Duration, &mut Keyboard) -> Result<()>>, Duration, SystemTime)>,
 }
 
 impl<T: 'static + Sized> HandlerBuilder<T> {
@@ -34,13 +37,14 @@ impl<T: 'static + Sized> HandlerBuilder<T> {
             user_data: user_data,
             init_fn: None,
             accept_key_fn: None,
+            swallow_key_fn: None,
             handle_key_fn: None,
             handle_time_fn: None,
         }
     }
 
     pub fn init_fn<F>(mut self, f: F) -> Self
-            where F: 'static + Fn(&mut T, &mut Keyboard) -> UsbResult<()> {
+            where F: 'static + Fn(&mut T, &mut Keyboard) -> Result<()> {
         self.init_fn = Some(Box::new(f));
         self
     }
@@ -49,13 +53,20 @@ impl<T: 'static + Sized> HandlerBuilder<T> {
         self.accept_key_fn = Some(Box::new(f));
         self
     }
+    /// Sets the function deciding whether an accepted key event is swallowed,
+    /// i.e. not passed through to the OS if passthrough is enabled.
+    pub fn swallow_key_fn<F>(mut self, f: F) -> Self
+            where F: 'static + Fn(&T, &KeyEvent) -> bool {
+        self.swallow_key_fn = Some(Box::new(f));
+        self
+    }
     pub fn handle_key_fn<F>(mut self, f: F) -> Self
-            where F: 'static + Fn(&mut T, &KeyEvent, &mut Keyboard) -> UsbResult<()> {
+            where F: 'static + Fn(&mut T, &KeyEvent, &mut Keyboard) -> Result<()> {
         self.handle_key_fn = Some(Box::new(f));
         self
     }
     pub fn handle_time_fn<F>(mut self, f: F, sleep_duration: Duration) -> Self
-            where F: 'static + Fn(&mut T, Duration, &mut Keyboard) -> UsbResult<()> {
+            where F: 'static + Fn(&mut T, Duration, &mut Keyboard) -> Result<()> {
         self.handle_time_fn = Some((Box::new(f), sleep_duration, SystemTime::now()));
         self
     }
@@ -65,7 +76,7 @@ impl<T: 'static + Sized> HandlerBuilder<T> {
 }
 
 impl<T: Sized> GenericHandler for HandlerBuilder<T> {
-    fn init(&mut self, keyboard: &mut Keyboard) -> UsbResult<()> {
+    fn init(&mut self, keyboard: &mut Keyboard) -> Result<()> {
         match &self.init_fn {
             &Some(ref f) => f(&mut self.user_data, keyboard),
             &None => Ok(())
@@ -77,13 +88,19 @@ impl<T: Sized> GenericHandler for HandlerBuilder<T> {
             &None => false
         }
     }
-    fn handle_key(&mut self, evt: &KeyEvent, keyboard: &mut Keyboard) -> UsbResult<()> {
+    fn swallow_key(&self, evt: &KeyEvent) -> bool {
+        match &self.swallow_key_fn {
+            &Some(ref f) => f(&self.user_data, evt),
+            &None => false
+        }
+    }
+    fn handle_key(&mut self, evt: &KeyEvent, keyboard: &mut Keyboard) -> Result<()> {
         match &self.handle_key_fn {
             &Some(ref f) => f(&mut self.user_data, evt, keyboard),
             &None => Ok(())
         }
     }
-    fn handle_time(&mut self, keyboard: &mut Keyboard) -> UsbResult<()> {
+    fn handle_time(&mut self, keyboard: &mut Keyboard) -> Result<()> {
         match &mut self.handle_time_fn {
             &mut Some((ref f, _, ref mut last_called)) => {
                 let res = f(&mut self.user_data, last_called.elapsed().unwrap(), keyboard);
@@ -106,9 +123,71 @@ impl<T: Sized> GenericHandler for HandlerBuilder<T> {
     }
 }
 
+/// Receives all key events which weren't swallowed by a handler,
+/// e.g. to pass them through to the OS.
+pub trait KeySink {
+    fn emit(&mut self, event: &KeyEvent) -> Result<()>;
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum KeyEvent {
     KeyPressed(Key),
     KeyReleased(Key),
 }
 
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use keys::{Key, StandardKey};
+    use keyboard::KeyboardImpl;
+    use fake::FakeDevice;
+    use error::Result;
+    use super::{HandlerBuilder, KeyEvent, KeySink};
+
+    struct Recorder(Rc<RefCell<Vec<KeyEvent>>>);
+
+    impl KeySink for Recorder {
+        fn emit(&mut self, event: &KeyEvent) -> Result<()> {
+            self.0.borrow_mut().push(event.clone());
+            Ok(())
+        }
+    }
+
+    fn key(event: &KeyEvent) -> &Key {
+        match event {
+            &KeyEvent::KeyPressed(ref key) | &KeyEvent::KeyReleased(ref key) => key,
+        }
+    }
+
+    #[test]
+    fn swallowed_keys_are_not_passed_through() {
+        let device = FakeDevice::new();
+        let mut keyboard = KeyboardImpl::with_transport(Box::new(device.clone()));
+        let passed = Rc::new(RefCell::new(Vec::new()));
+        keyboard.set_passthrough(Some(Box::new(Recorder(passed.clone()))));
+        let handled = Rc::new(RefCell::new(Vec::new()));
+        // handles A and B, but only swallows A
+        keyboard.add_handler(HandlerBuilder::new(handled.clone())
+            .accept_key_fn(|_, evt| *key(evt) != Key::Standard(StandardKey::C))
+            .swallow_key_fn(|_, evt| *key(evt) == Key::Standard(StandardKey::A))
+            .handle_key_fn(|handled, evt, _| {
+                handled.borrow_mut().push(evt.clone());
+                Ok(())
+            })
+            .build());
+        // A, B and C pressed and released one after another
+        for &code in [0x04, 0x05, 0x06].iter() {
+            device.emit_interrupt(0x81, vec![0x00, 0x00, code, 0x00, 0x00, 0x00, 0x00, 0x00]);
+            device.emit_interrupt(0x81, vec![0x00; 8]);
+        }
+        while keyboard.poll_events().unwrap().is_some() {}
+        let pressed = |k| KeyEvent::KeyPressed(Key::Standard(k));
+        let released = |k| KeyEvent::KeyReleased(Key::Standard(k));
+        assert_eq!(*handled.borrow(), vec![pressed(StandardKey::A), released(StandardKey::A),
+            pressed(StandardKey::B), released(StandardKey::B)]);
+        assert_eq!(*passed.borrow(), vec![pressed(StandardKey::B), released(StandardKey::B),
+            pressed(StandardKey::C), released(StandardKey::C)]);
+    }
+}
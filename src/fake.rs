@@ -0,0 +1,262 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use libusb::{Result as UsbResult, Error as UsbError};
+use handle::{Transport, ControlPacket};
+use model::{self, DeviceModel};
+
+struct FakeState {
+    pending: VecDeque<UsbResult<(u8, Vec<u8>)>>,
+    sent_control: Vec<ControlPacket>,
+    sent_interrupt: Vec<(u8, Vec<u8>)>,
+    auto_ack: bool,
+    connected: bool,
+    reconnects: u32,
+    address: u8,
+    model: &'static DeviceModel,
+}
+
+/// In-memory G910 which can be used as `Transport` without any hardware.
+///
+/// Every control packet sent to it completes immediately on endpoint 0x80
+/// and, if auto ack is enabled (default), is acknowledged on endpoint 0x82
+/// just like the real keyboard does.
+/// Key reports and errors can be scripted with `emit_interrupt` and `emit_error`.
+///
+/// Like a replugged keyboard, the device gets a new address on bus 1 on each reconnect.
+///
+/// `FakeDevice` is a cheap handle to shared state, so a clone can be kept
+/// to inspect and script the device after handing it to a keyboard.
+#[derive(Clone)]
+pub struct FakeDevice {
+    state: Arc<Mutex<FakeState>>,
+}
+
+impl FakeDevice {
+    pub fn new() -> FakeDevice {
+        FakeDevice::with_model(&model::G910)
+    }
+
+    pub fn with_model(model: &'static DeviceModel) -> FakeDevice {
+        FakeDevice {
+            state: Arc::new(Mutex::new(FakeState {
+                pending: VecDeque::new(),
+                sent_control: Vec::new(),
+                sent_interrupt: Vec::new(),
+                auto_ack: true,
+                connected: true,
+                reconnects: 0,
+                address: 1,
+                model: model,
+            })),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<FakeState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Queues an interrupt report, which will be returned by the next `recv`.
+    pub fn emit_interrupt(&self, endpoint_direction: u8, buf: Vec<u8>) {
+        self.state().pending.push_back(Ok((endpoint_direction, buf)));
+    }
+
+    /// Queues an error, which will be returned by the next `recv`.
+    pub fn emit_error(&self, err: UsbError) {
+        self.state().pending.push_back(Err(err));
+    }
+
+    /// Queues the acknowledgement the keyboard sends for given control packet.
+    pub fn ack(&self, packet: &ControlPacket) {
+        self.emit_interrupt(0x82, ack_for(packet));
+    }
+
+    pub fn set_auto_ack(&self, enabled: bool) {
+        self.state().auto_ack = enabled;
+    }
+
+    /// Simulates unplugging (false) or replugging (true) the keyboard.
+    ///
+    /// While disconnected all operations fail with `NoDevice`.
+    pub fn set_connected(&self, connected: bool) {
+        let mut state = self.state();
+        state.connected = connected;
+        if !connected {
+            state.pending.clear();
+        }
+    }
+
+    /// Returns all control packets sent so far.
+    pub fn sent_control_packets(&self) -> Vec<ControlPacket> {
+        self.state().sent_control.clone()
+    }
+
+    /// Returns and forgets all control packets sent so far.
+    pub fn take_sent_control_packets(&self) -> Vec<ControlPacket> {
+        ::std::mem::replace(&mut self.state().sent_control, Vec::new())
+    }
+
+    /// Returns all submitted interrupt transfers as (endpoint, buffer).
+    pub fn sent_interrupts(&self) -> Vec<(u8, Vec<u8>)> {
+        self.state().sent_interrupt.clone()
+    }
+
+    /// Returns the number of transfers waiting to be received.
+    pub fn pending(&self) -> usize {
+        self.state().pending.len()
+    }
+
+    /// Returns how often the device has been reconnected successfully.
+    pub fn reconnects(&self) -> u32 {
+        self.state().reconnects
+    }
+}
+
+fn ack_for(packet: &ControlPacket) -> Vec<u8> {
+    // the ack echoes the packet header with the short report id
+    let mut buf = vec![0x11];
+    buf.extend(packet.buf().iter().skip(1).take(3).cloned());
+    buf.resize(20, 0u8);
+    buf
+}
+
+impl Transport for FakeDevice {
+    fn send_control(&mut self, packet: ControlPacket) -> UsbResult<()> {
+        let mut state = self.state();
+        if !state.connected {
+            return Err(UsbError::NoDevice);
+        }
+        state.pending.push_back(Ok((packet.endpoint_direction(), packet.buf().to_vec())));
+        if state.auto_ack {
+            state.pending.push_back(Ok((0x82, ack_for(&packet))));
+        }
+        state.sent_control.push(packet);
+        Ok(())
+    }
+
+    fn send_interrupt(&mut self, endpoint_direction: u8, buf: Vec<u8>,
+                      _timeout: Duration) -> UsbResult<()> {
+        let mut state = self.state();
+        if !state.connected {
+            return Err(UsbError::NoDevice);
+        }
+        state.sent_interrupt.push((endpoint_direction, buf));
+        Ok(())
+    }
+
+    /// Returns the next queued transfer.
+    ///
+    /// Doesn't block: if nothing is queued, None is returned immediately
+    /// as if the timeout had elapsed.
+    fn recv(&mut self, _timeout: Duration) -> Option<UsbResult<(u8, Vec<u8>)>> {
+        let mut state = self.state();
+        if !state.connected {
+            return Some(Err(UsbError::NoDevice));
+        }
+        state.pending.pop_front()
+    }
+
+    fn reconnect(&mut self) -> UsbResult<()> {
+        let mut state = self.state();
+        if !state.connected {
+            return Err(UsbError::NoDevice);
+        }
+        state.pending.clear();
+        state.reconnects += 1;
+        state.address += 1;
+        Ok(())
+    }
+
+    fn bus_address(&self) -> Option<(u8, u8)> {
+        let state = self.state();
+        if state.connected {
+            Some((1, state.address))
+        } else {
+            None
+        }
+    }
+
+    /// Listens on both interfaces like `Handle` in `ConnectionMode::Full`.
+    fn listening(&self) -> Vec<(u8, usize)> {
+        vec![(0x81, 8), (0x82, 64)]
+    }
+
+    fn model(&self) -> &'static DeviceModel {
+        self.state().model
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use libusb::Error as UsbError;
+    use handle::{ControlPacket, Transport};
+    use model;
+    use super::FakeDevice;
+
+    fn packet(header: &[u8]) -> ControlPacket {
+        let mut buf = header.to_vec();
+        buf.resize(20, 0u8);
+        ControlPacket::new(buf, 0x80, 0x21, 9, 0x0212, 0x0001, Duration::from_secs(10))
+    }
+
+    #[test]
+    fn control_packets_complete_and_are_acknowledged() {
+        let mut device = FakeDevice::new();
+        let sent = packet(&[0x11, 0xff, 0x0f, 0x5b]);
+        device.send_control(sent.clone()).unwrap();
+        assert_eq!(device.sent_control_packets(), vec![sent.clone()]);
+        assert_eq!(device.recv(Duration::from_secs(0)).unwrap().unwrap(), (0x80, sent.buf().to_vec()));
+        let mut ack = vec![0x11, 0xff, 0x0f, 0x5b];
+        ack.resize(20, 0u8);
+        assert_eq!(device.recv(Duration::from_secs(0)).unwrap().unwrap(), (0x82, ack));
+        assert!(device.recv(Duration::from_secs(0)).is_none());
+    }
+
+    #[test]
+    fn manual_ack() {
+        let mut device = FakeDevice::new();
+        device.set_auto_ack(false);
+        let sent = packet(&[0x12, 0xff, 0x0f, 0x3b]);
+        device.send_control(sent.clone()).unwrap();
+        assert_eq!(device.pending(), 1);
+        device.ack(&sent);
+        assert_eq!(device.pending(), 2);
+        assert_eq!(device.take_sent_control_packets(), vec![sent]);
+        assert!(device.sent_control_packets().is_empty());
+    }
+
+    #[test]
+    fn scripted_transfers_are_received_in_order() {
+        let mut device = FakeDevice::with_model(&model::G810);
+        assert_eq!(device.model(), &model::G810);
+        device.emit_interrupt(0x81, vec![0x00; 8]);
+        device.emit_error(UsbError::Io);
+        assert_eq!(device.recv(Duration::from_secs(0)).unwrap().unwrap(), (0x81, vec![0x00; 8]));
+        match device.recv(Duration::from_secs(0)) {
+            Some(Err(UsbError::Io)) => {},
+            res => panic!("unexpected result {:?}", res),
+        }
+        device.send_interrupt(0x82, vec![0; 64], Duration::from_secs(1)).unwrap();
+        assert_eq!(device.sent_interrupts(), vec![(0x82, vec![0; 64])]);
+    }
+
+    #[test]
+    fn disconnected_device_fails_with_no_device() {
+        let mut device = FakeDevice::new();
+        device.emit_interrupt(0x81, vec![0x00; 8]);
+        device.set_connected(false);
+        assert_eq!(device.pending(), 0);
+        assert_eq!(device.send_control(packet(&[0x11, 0xff, 0x0f, 0x5b])), Err(UsbError::NoDevice));
+        assert_eq!(device.send_interrupt(0x82, vec![0; 64], Duration::from_secs(1)), Err(UsbError::NoDevice));
+        match device.recv(Duration::from_secs(0)) {
+            Some(Err(UsbError::NoDevice)) => {},
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(device.reconnect(), Err(UsbError::NoDevice));
+        assert_eq!(device.reconnects(), 0);
+        device.set_connected(true);
+        assert_eq!(device.reconnect(), Ok(()));
+        assert_eq!(device.reconnects(), 1);
+    }
+}
@@ -0,0 +1,378 @@
+use keys::*;
+use model::DeviceModel;
+
+// gap between keys which are still considered neighbors
+const NEIGHBOR_TOLERANCE: f32 = 0.3;
+
+/// Physical layout of the main key block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Layout {
+    /// 105 keys with a tall return key and an additional key next to the left shift.
+    Iso,
+    /// 104 keys with a wide return key and a wide left shift.
+    Ansi,
+}
+
+/// Rectangle on the keyboard in key units, with the origin in the top left corner.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rect {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Rect {
+    pub fn new(x: f32, y: f32, width: f32, height: f32) -> Rect {
+        Rect {
+            x: x,
+            y: y,
+            width: width,
+            height: height,
+        }
+    }
+
+    pub fn center(&self) -> (f32, f32) {
+        (self.x + self.width / 2.0, self.y + self.height / 2.0)
+    }
+
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+    }
+
+    /// Returns whether both rectangles overlap, touching edges don't count.
+    pub fn intersects(&self, other: &Rect) -> bool {
+        self.x < other.x + other.width && other.x < self.x + self.width
+            && self.y < other.y + other.height && other.y < self.y + self.height
+    }
+
+    /// Returns the distance of given point to the closest point of the rectangle.
+    pub fn distance(&self, x: f32, y: f32) -> f32 {
+        let dx = (self.x - x).max(x - (self.x + self.width)).max(0.0);
+        let dy = (self.y - y).max(y - (self.y + self.height)).max(0.0);
+        (dx * dx + dy * dy).sqrt()
+    }
+
+    fn grow(&self, by: f32) -> Rect {
+        Rect::new(self.x - by, self.y - by, self.width + 2.0 * by, self.height + 2.0 * by)
+    }
+}
+
+/// Physical position of every lit key, for effects working on the keyboard's surface.
+///
+/// Positions are measured on the G910 in key units, where a letter key is 1x1.
+/// The G-keys G1 to G5 form the leftmost column, G6 to G9 and the logos sit in the
+/// top row above the function keys.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Geometry {
+    keys: Vec<(Key, Rect)>,
+}
+
+impl Geometry {
+    /// Returns the geometry of the G910 with given layout.
+    pub fn new(layout: Layout) -> Geometry {
+        let mut keys = Vec::new();
+        g910(&mut keys, layout);
+        Geometry {
+            keys: keys,
+        }
+    }
+
+    /// Returns the geometry of given layout with only the keys the model can light.
+    ///
+    /// Other models share the G910's positions, leaving its G-key column empty.
+    pub fn for_model(model: &DeviceModel, layout: Layout) -> Geometry {
+        let mut geometry = Geometry::new(layout);
+        geometry.keys.retain(|&(ref key, _)| model.supports(key));
+        geometry
+    }
+
+    /// Returns all keys with their positions.
+    pub fn keys(&self) -> &[(Key, Rect)] {
+        &self.keys
+    }
+
+    pub fn rect(&self, key: &Key) -> Option<Rect> {
+        self.keys.iter()
+            .find(|&&(ref k, _)| k == key)
+            .map(|&(_, rect)| rect)
+    }
+
+    /// Returns the width and height of the bounding box of all keys.
+    pub fn size(&self) -> (f32, f32) {
+        self.keys.iter().fold((0.0, 0.0), |(w, h): (f32, f32), &(_, ref r)| {
+            (w.max(r.x + r.width), h.max(r.y + r.height))
+        })
+    }
+
+    /// Returns all keys overlapping given rectangle.
+    pub fn keys_in_rect(&self, rect: &Rect) -> Vec<Key> {
+        self.keys.iter()
+            .filter(|&&(_, ref r)| r.intersects(rect))
+            .map(|&(ref k, _)| k.clone())
+            .collect()
+    }
+
+    /// Returns the key at or closest to given point, None if there are no keys.
+    pub fn nearest_key(&self, x: f32, y: f32) -> Option<Key> {
+        let mut nearest = None;
+        for &(ref key, ref rect) in self.keys.iter() {
+            let distance = rect.distance(x, y);
+            match nearest {
+                Some((_, d)) if d <= distance => {},
+                _ => nearest = Some((key, distance)),
+            }
+        }
+        nearest.map(|(key, _)| key.clone())
+    }
+
+    /// Returns all keys directly adjacent to given key, including diagonally adjacent ones.
+    pub fn neighbors(&self, key: &Key) -> Vec<Key> {
+        let area = match self.rect(key) {
+            Some(rect) => rect.grow(NEIGHBOR_TOLERANCE),
+            None => return Vec::new(),
+        };
+        self.keys.iter()
+            .filter(|&&(ref k, ref r)| k != key && r.intersects(&area))
+            .map(|&(ref k, _)| k.clone())
+            .collect()
+    }
+
+    /// Returns all rows from top to bottom, each from left to right.
+    ///
+    /// A key belongs to the row of its top edge, so the numpad's plus and return key
+    /// are part of the upper row they span.
+    pub fn rows(&self) -> Vec<Vec<Key>> {
+        let mut sorted: Vec<_> = self.keys.iter().collect();
+        sorted.sort_by(|&&(_, ref a), &&(_, ref b)| {
+            (a.y, a.x).partial_cmp(&(b.y, b.x)).unwrap()
+        });
+        let mut rows: Vec<Vec<Key>> = Vec::new();
+        let mut last_y = None;
+        for &&(ref key, ref rect) in sorted.iter() {
+            if last_y != Some(rect.y) {
+                rows.push(Vec::new());
+                last_y = Some(rect.y);
+            }
+            rows.last_mut().unwrap().push(key.clone());
+        }
+        rows
+    }
+
+    /// Returns all keys spanning the vertical line at given x, from top to bottom.
+    pub fn column(&self, x: f32) -> Vec<Key> {
+        let mut column: Vec<_> = self.keys.iter()
+            .filter(|&&(_, ref r)| x >= r.x && x < r.x + r.width)
+            .collect();
+        column.sort_by(|&&(_, ref a), &&(_, ref b)| a.y.partial_cmp(&b.y).unwrap());
+        column.into_iter().map(|&(ref k, _)| k.clone()).collect()
+    }
+
+    /// Returns the columns through the center of every key unit from left to right,
+    /// skipping empty ones.
+    ///
+    /// Keys wider than one unit are part of multiple columns.
+    pub fn columns(&self) -> Vec<Vec<Key>> {
+        let (width, _) = self.size();
+        let units = (width / 0.25).ceil() as u32;
+        let mut columns: Vec<Vec<Key>> = Vec::new();
+        // step in quarter units, as keys are staggered by quarter units
+        for i in 0..units {
+            let column = self.column(i as f32 * 0.25 + 0.125);
+            if column.len() > 0 && columns.last() != Some(&column) {
+                columns.push(column);
+            }
+        }
+        columns
+    }
+}
+
+// places given keys with their widths next to each other, starting at x
+fn row<K: Into<Key> + Copy>(keys: &mut Vec<(Key, Rect)>, x: f32, y: f32, row: &[(K, f32)]) {
+    let mut x = x;
+    for &(key, width) in row {
+        keys.push((key.into(), Rect::new(x, y, width, 1.0)));
+        x += width;
+    }
+}
+
+fn g910(keys: &mut Vec<(Key, Rect)>, layout: Layout) {
+    use keys::StandardKey::*;
+    use keys::GamingKey::*;
+
+    // left of the main block
+    const MAIN: f32 = 1.25;
+    // right of the main block
+    const NAVIGATION: f32 = MAIN + 15.25;
+    const NUMPAD: f32 = MAIN + 18.5;
+
+    // G-keys and logos
+    keys.push((Logo::G.into(), Rect::new(MAIN, 0.0, 1.0, 1.0)));
+    row(keys, MAIN + 2.0, 0.0, &[(G6, 1.0), (G7, 1.0), (G8, 1.0), (G9, 1.0)]);
+    keys.push((Logo::G910.into(), Rect::new(NAVIGATION, 0.0, 3.0, 1.0)));
+    for (i, &g) in [G1, G2, G3, G4, G5].iter().enumerate() {
+        keys.push((g.into(), Rect::new(0.0, 2.5 + i as f32, 1.0, 1.0)));
+    }
+
+    // function keys
+    let y = 1.25;
+    row(keys, MAIN, y, &[(Esc, 1.0)]);
+    row(keys, MAIN + 2.0, y, &[(F1, 1.0), (F2, 1.0), (F3, 1.0), (F4, 1.0)]);
+    row(keys, MAIN + 6.5, y, &[(F5, 1.0), (F6, 1.0), (F7, 1.0), (F8, 1.0)]);
+    row(keys, MAIN + 11.0, y, &[(F9, 1.0), (F10, 1.0), (F11, 1.0), (F12, 1.0)]);
+    row(keys, NAVIGATION, y, &[(Print, 1.0), (ScrollLock, 1.0), (Pause, 1.0)]);
+
+    // number row
+    let y = 2.5;
+    row(keys, MAIN, y, &[(Circumflex, 1.0), (_1, 1.0), (_2, 1.0), (_3, 1.0), (_4, 1.0), (_5, 1.0),
+        (_6, 1.0), (_7, 1.0), (_8, 1.0), (_9, 1.0), (_0, 1.0), (Sz, 1.0), (Tick, 1.0), (Backspace, 2.0)]);
+    row(keys, NAVIGATION, y, &[(Insert, 1.0), (Home, 1.0), (PageUp, 1.0)]);
+    row(keys, NUMPAD, y, &[(NumLock, 1.0), (NumSlash, 1.0), (NumStar, 1.0), (NumMinus, 1.0)]);
+
+    // top letter row
+    let y = 3.5;
+    row(keys, MAIN, y, &[(Tab, 1.5), (Q, 1.0), (W, 1.0), (E, 1.0), (R, 1.0), (T, 1.0), (Z, 1.0),
+        (U, 1.0), (I, 1.0), (O, 1.0), (P, 1.0), (Uuml, 1.0), (Plus, 1.0)]);
+    match layout {
+        Layout::Iso => keys.push((Return.into(), Rect::new(MAIN + 13.75, y, 1.25, 2.0))),
+        Layout::Ansi => row(keys, MAIN + 13.5, y, &[(Pipe, 1.5)]),
+    }
+    row(keys, NAVIGATION, y, &[(Delete, 1.0), (End, 1.0), (PageDown, 1.0)]);
+    row(keys, NUMPAD, y, &[(Num7, 1.0), (Num8, 1.0), (Num9, 1.0)]);
+    keys.push((NumPlus.into(), Rect::new(NUMPAD + 3.0, y, 1.0, 2.0)));
+
+    // middle letter row
+    let y = 4.5;
+    row(keys, MAIN, y, &[(CapsLock, 1.75), (A, 1.0), (S, 1.0), (D, 1.0), (F, 1.0), (G, 1.0),
+        (H, 1.0), (J, 1.0), (K, 1.0), (L, 1.0), (Ouml, 1.0), (Auml, 1.0)]);
+    match layout {
+        Layout::Iso => row(keys, MAIN + 12.75, y, &[(Sharp, 1.0)]),
+        Layout::Ansi => row(keys, MAIN + 12.75, y, &[(Return, 2.25)]),
+    }
+    row(keys, NUMPAD, y, &[(Num4, 1.0), (Num5, 1.0), (Num6, 1.0)]);
+
+    // bottom letter row
+    let y = 5.5;
+    match layout {
+        Layout::Iso => row(keys, MAIN, y, &[(LeftShift, 1.25), (SmallerThan, 1.0)]),
+        Layout::Ansi => row(keys, MAIN, y, &[(LeftShift, 2.25)]),
+    }
+    row(keys, MAIN + 2.25, y, &[(Y, 1.0), (X, 1.0), (C, 1.0), (V, 1.0), (B, 1.0), (N, 1.0),
+        (M, 1.0), (Comma, 1.0), (Dot, 1.0), (Minus, 1.0), (RightShift, 2.75)]);
+    row(keys, NAVIGATION + 1.0, y, &[(Up, 1.0)]);
+    row(keys, NUMPAD, y, &[(Num1, 1.0), (Num2, 1.0), (Num3, 1.0)]);
+    keys.push((NumReturn.into(), Rect::new(NUMPAD + 3.0, y, 1.0, 2.0)));
+
+    // space row
+    let y = 6.5;
+    row(keys, MAIN, y, &[(LeftControl, 1.25), (LeftWindows, 1.25), (LeftAlt, 1.25), (Space, 6.25),
+        (RightAlt, 1.25), (RightWindows, 1.25), (Menu, 1.25), (RightControl, 1.25)]);
+    row(keys, NAVIGATION, y, &[(Left, 1.0), (Down, 1.0), (Right, 1.0)]);
+    row(keys, NUMPAD, y, &[(Num0, 2.0), (NumComma, 1.0)]);
+}
+
+#[cfg(test)]
+mod tests {
+    use keys::*;
+    use keys::StandardKey::*;
+    use model;
+    use super::{Geometry, Layout, Rect};
+
+    fn key<K: Into<Key>>(key: K) -> Key {
+        key.into()
+    }
+
+    #[test]
+    fn iso_and_ansi() {
+        let iso = Geometry::new(Layout::Iso);
+        let ansi = Geometry::new(Layout::Ansi);
+        // tall return spanning two rows next to Sharp, and a short left shift
+        assert_eq!(iso.rect(&key(Return)), Some(Rect::new(15.0, 3.5, 1.25, 2.0)));
+        assert_eq!(iso.rect(&key(LeftShift)), Some(Rect::new(1.25, 5.5, 1.25, 1.0)));
+        assert!(iso.rect(&key(Sharp)).is_some());
+        assert!(iso.rect(&key(SmallerThan)).is_some());
+        assert!(iso.rect(&key(Pipe)).is_none());
+        // wide return in the middle row, pipe above it and a wide left shift
+        assert_eq!(ansi.rect(&key(Return)), Some(Rect::new(14.0, 4.5, 2.25, 1.0)));
+        assert_eq!(ansi.rect(&key(LeftShift)), Some(Rect::new(1.25, 5.5, 2.25, 1.0)));
+        assert!(ansi.rect(&key(Pipe)).is_some());
+        assert!(ansi.rect(&key(Sharp)).is_none());
+        assert!(ansi.rect(&key(SmallerThan)).is_none());
+        assert_eq!(iso.keys().len(), ansi.keys().len() + 1);
+    }
+
+    #[test]
+    fn main_block_rows_are_15_units_wide() {
+        for &layout in [Layout::Iso, Layout::Ansi].iter() {
+            let geometry = Geometry::new(layout);
+            // number row to space row
+            for i in 0..5 {
+                let y = 3.0 + i as f32;
+                let width: f32 = geometry.keys().iter()
+                    .filter(|&&(_, ref r)| r.x >= 1.25 && r.x < 16.5 && r.y <= y && y < r.y + r.height)
+                    .map(|&(_, ref r)| r.width)
+                    .sum();
+                // the ISO return is as wide as its lower part, leaving a gap in the upper row
+                let expected = if layout == Layout::Iso && i == 1 { 14.75 } else { 15.0 };
+                assert_eq!(width, expected, "{:?} row {}", layout, i);
+            }
+        }
+    }
+
+    #[test]
+    fn models_without_gaming_keys() {
+        let g910 = Geometry::for_model(&model::G910, Layout::Iso);
+        let g610 = Geometry::for_model(&model::G610, Layout::Iso);
+        let gaming = |g: &Geometry| g.keys().iter().filter(|&&(ref k, _)| match *k {
+            Key::Gaming(_) => true,
+            _ => false,
+        }).count();
+        assert_eq!(gaming(&g910), 9);
+        assert_eq!(gaming(&g610), 0);
+        assert!(g610.rect(&key(Logo::G)).is_none());
+        // the G-key column stays empty
+        assert_eq!(g610.rect(&key(Esc)), g910.rect(&key(Esc)));
+        assert_eq!(g910.columns()[0], vec![key(GamingKey::G1), key(GamingKey::G2), key(GamingKey::G3),
+            key(GamingKey::G4), key(GamingKey::G5)]);
+        assert_eq!(g610.columns()[0], vec![key(Esc), key(Circumflex), key(Tab), key(CapsLock),
+            key(LeftShift), key(LeftControl)]);
+    }
+
+    #[test]
+    fn rows() {
+        let rows = Geometry::for_model(&model::G610, Layout::Iso).rows();
+        assert_eq!(rows.len(), 6);
+        assert_eq!(rows[0][..2], [key(Esc), key(F1)]);
+        // the numpad plus belongs to the upper row it spans
+        assert_eq!(rows[2].last(), Some(&key(NumPlus)));
+        assert_eq!(rows[5].last(), Some(&key(NumComma)));
+    }
+
+    #[test]
+    fn keys_in_rect() {
+        let geometry = Geometry::new(Layout::Iso);
+        // touching edges don't count
+        assert_eq!(geometry.keys_in_rect(&Rect::new(2.75, 3.5, 3.0, 1.0)), vec![key(Q), key(W), key(E)]);
+        assert_eq!(geometry.keys_in_rect(&Rect::new(15.1, 4.9, 0.2, 0.2)), vec![key(Return)]);
+        assert!(geometry.keys_in_rect(&Rect::new(1.25, 2.25, 4.0, 0.25)).is_empty());
+    }
+
+    #[test]
+    fn nearest_key() {
+        let geometry = Geometry::new(Layout::Iso);
+        assert_eq!(geometry.nearest_key(3.75, 1.75), Some(key(F1)));
+        // outside of the keyboard
+        assert_eq!(geometry.nearest_key(-5.0, 3.0), Some(key(GamingKey::G1)));
+        assert_eq!(geometry.nearest_key(100.0, 100.0), Some(key(NumReturn)));
+        assert!(Geometry { keys: Vec::new() }.nearest_key(0.0, 0.0).is_none());
+    }
+
+    #[test]
+    fn neighbors_of_edge_key() {
+        let geometry = Geometry::new(Layout::Iso);
+        assert_eq!(geometry.neighbors(&key(NumComma)),
+            vec![key(Num2), key(Num3), key(NumReturn), key(Num0)]);
+        assert_eq!(geometry.neighbors(&key(GamingKey::G5)), vec![key(GamingKey::G4), key(LeftShift), key(LeftControl)]);
+        assert!(geometry.neighbors(&key(MediaKey::Mute)).is_empty());
+    }
+}
@@ -1,11 +1,59 @@
+use std::os::unix::io::RawFd;
 use std::time::Duration;
-use libusb::{Transfer, Result as UsbResult};
+use libusb::{Transfer, Result as UsbResult, Error as UsbError};
 use utils::UsbWrapper;
+use device::{DeviceInfo, DeviceSelector, ConnectionMode};
+use model::DeviceModel;
+use hotplug::HotplugMonitor;
+use consts;
+use utils::Hex;
 
 pub trait ToControlPacket {
     fn to_control_packet(self) -> ControlPacket;
 }
 
+/// Everything the keyboard needs from the underlying device connection.
+///
+/// `Handle` talks to a real G910 over libusb, `FakeDevice` is an in-memory
+/// stand-in which can be used without any hardware attached.
+pub trait Transport {
+    fn send_control(&mut self, packet: ControlPacket) -> UsbResult<()>;
+    fn send_interrupt(&mut self, endpoint_direction: u8, buf: Vec<u8>, timeout: Duration) -> UsbResult<()>;
+    /// Waits up to `timeout` for the next completed transfer.
+    ///
+    /// Returns the endpoint (including direction bit) and the received data,
+    /// or None if the timeout elapsed.
+    fn recv(&mut self, timeout: Duration) -> Option<UsbResult<(u8, Vec<u8>)>>;
+    fn reconnect(&mut self) -> UsbResult<()>;
+    /// Blocks until the keyboard is plugged in or the timeout elapses, indefinitely if None.
+    ///
+    /// Returns whether the keyboard is present, or None if the transport
+    /// can't detect the keyboard's arrival, in which case `reconnect` must be polled.
+    fn wait_for_device(&mut self, _timeout: Option<Duration>) -> Option<UsbResult<bool>> {
+        None
+    }
+    /// Returns the file descriptors which become ready when `recv` has something to return,
+    /// together with the poll events of interest.
+    ///
+    /// They may change after reconnecting.
+    /// Transports which can't be polled return an empty list.
+    fn pollfds(&self) -> Vec<(RawFd, i16)> {
+        Vec::new()
+    }
+    /// Returns bus number and address of the connected keyboard, None if unknown or disconnected.
+    fn bus_address(&self) -> Option<(u8, u8)> {
+        None
+    }
+    /// Returns endpoint (including direction bit) and length of the interrupt transfers
+    /// the transport submits by itself to receive reports, after connecting and after each completion.
+    fn listening(&self) -> Vec<(u8, usize)> {
+        Vec::new()
+    }
+    /// Returns the model of the connected keyboard.
+    fn model(&self) -> &'static DeviceModel;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ControlPacket {
     buf: Vec<u8>,
     endpoint_direction: u8,
@@ -29,33 +77,136 @@ impl ControlPacket {
             timeout: timeout,
         }
     }
+
+    pub fn buf(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn endpoint_direction(&self) -> u8 {
+        self.endpoint_direction
+    }
+
+    pub fn request_type(&self) -> u8 {
+        self.request_type
+    }
+
+    pub fn request(&self) -> u8 {
+        self.request
+    }
+
+    pub fn value(&self) -> u16 {
+        self.value
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
 }
 
 pub struct Handle {
     usb_wrapper: Option<UsbWrapper>,
+    // used to find the same keyboard again on reconnect
+    selector: DeviceSelector,
+    mode: ConnectionMode,
+    // None if hotplug isn't supported
+    hotplug: Option<HotplugMonitor>,
+    // kept separately, as usb_wrapper is None while reconnecting
+    model: &'static DeviceModel,
 }
 
 impl Handle {
     pub fn new() -> UsbResult<Handle> {
-        let usb_wrapper = try!(UsbWrapper::new());
+        Handle::open(DeviceSelector::Any)
+    }
+
+    /// Opens the keyboard matching given selector.
+    pub fn open(selector: DeviceSelector) -> UsbResult<Handle> {
+        Handle::open_with_mode(selector, ConnectionMode::Full)
+    }
+
+    /// Opens the keyboard matching given selector, only claiming the interfaces needed by `mode`.
+    pub fn open_with_mode(selector: DeviceSelector, mode: ConnectionMode) -> UsbResult<Handle> {
+        let usb_wrapper = try!(UsbWrapper::new(&selector, mode));
+        let selector = usb_wrapper.info.selector();
+        let hotplug = HotplugMonitor::new(consts::VENDOR_ID, usb_wrapper.info.product_id, selector.clone());
+        // only supported models are opened
+        let model = try!(usb_wrapper.info.model().ok_or(UsbError::NotSupported));
         let mut handle = Handle {
             usb_wrapper: Some(usb_wrapper),
+            selector: selector,
+            mode: mode,
+            hotplug: hotplug,
+            model: model,
         };
-        try!(handle.listen_iface1(Duration::from_secs(3600*24*365)));
-        try!(handle.listen_iface2(Duration::from_secs(3600*24*365)));
+        try!(handle.listen());
         Ok(handle)
     }
 
-    pub fn reconnect(&mut self) -> UsbResult<()> {
+    fn listen(&mut self) -> UsbResult<()> {
+        for (endpoint_direction, len) in self.listening() {
+            let mut vec = Vec::new();
+            vec.resize(len, 0u8);
+            try!(self.send_interrupt(endpoint_direction, vec, Duration::from_secs(3600*24*365)));
+        }
+        Ok(())
+    }
+
+    /// Returns information about the currently opened keyboard.
+    pub fn device_info(&self) -> Option<&DeviceInfo> {
+        self.usb_wrapper.as_ref().map(|w| &w.info)
+    }
+
+    // None after a failed reconnect attempt, until the keyboard is reconnected
+    fn wrapper(&mut self) -> UsbResult<&mut UsbWrapper> {
+        self.usb_wrapper.as_mut().ok_or(UsbError::NoDevice)
+    }
+
+    pub fn listen_iface2(&mut self, timeout: Duration) -> UsbResult<()> {
+        let mut vec = Vec::new();
+        vec.resize(64, 0u8);
+        self.send_interrupt(0x82, vec, timeout)
+    }
+
+    pub fn listen_iface1(&mut self, timeout: Duration) -> UsbResult<()> {
+        let mut vec = Vec::new();
+        vec.resize(8, 0u8);
+        try!(self.send_interrupt(0x81, vec, timeout));
+        Ok(())
+    }
+}
+
+impl Transport for Handle {
+    fn reconnect(&mut self) -> UsbResult<()> {
         // We must drop the old one before creating a new one, because all
         // handles and locks on that device must be released first.
         drop(::std::mem::replace(&mut self.usb_wrapper, None));
-        self.usb_wrapper = Some(try!(UsbWrapper::new()));
-        Ok(())
+        let usb_wrapper = match UsbWrapper::new(&self.selector, self.mode) {
+            Ok(usb_wrapper) => usb_wrapper,
+            Err(e) => {
+                // wait for the keyboard to arrive again instead of retrying right away
+                match (&e, self.hotplug.as_mut()) {
+                    (&UsbError::NoDevice, Some(hotplug)) => hotplug.device_unavailable(),
+                    _ => {}
+                }
+                return Err(e);
+            },
+        };
+        self.usb_wrapper = Some(usb_wrapper);
+        // transfers of the old connection are gone, so we need to listen again
+        self.listen()
+    }
+
+    fn wait_for_device(&mut self, timeout: Option<Duration>) -> Option<UsbResult<bool>> {
+        self.hotplug.as_mut().map(|h| h.wait_for_device(timeout))
     }
 
-    pub fn send_control(&mut self, packet: ControlPacket) ->  UsbResult<()> {
-        let wrapper_ref = self.usb_wrapper.as_mut().unwrap();
+    fn send_control(&mut self, packet: ControlPacket) ->  UsbResult<()> {
+        trace!(target: consts::LOG_TRANSPORT, "Sending control packet: {}", Hex(&packet.buf));
+        let wrapper_ref = try!(self.wrapper());
         wrapper_ref.async_group.submit(Transfer::control(
                 wrapper_ref.handle,
                 packet.endpoint_direction,
@@ -68,9 +219,9 @@ impl Handle {
         ))
     }
 
-    pub fn send_interrupt(&mut self, endpoint_direction: u8, buf: Vec<u8>,
-                          timeout: Duration) -> UsbResult<()> {
-        let wrapper_ref = self.usb_wrapper.as_mut().unwrap();
+    fn send_interrupt(&mut self, endpoint_direction: u8, buf: Vec<u8>,
+                      timeout: Duration) -> UsbResult<()> {
+        let wrapper_ref = try!(self.wrapper());
         wrapper_ref.async_group.submit(Transfer::interrupt(
                 wrapper_ref.handle,
                 endpoint_direction,
@@ -79,19 +230,24 @@ impl Handle {
         ))
     }
 
-    pub fn recv(&mut self, timeout: Duration) -> Option<UsbResult<(u8, Vec<u8>)>> {
-        let mut transfer = match self.usb_wrapper.as_mut().unwrap().async_group.try_wait_any(timeout) {
+    fn recv(&mut self, timeout: Duration) -> Option<UsbResult<(u8, Vec<u8>)>> {
+        let res = match self.wrapper() {
+            Ok(wrapper_ref) => wrapper_ref.async_group.try_wait_any(timeout),
+            Err(err) => return Some(Err(err)),
+        };
+        let mut transfer = match res {
             Some(res) => match res {
                 Ok(transfer) => transfer,
                 Err(err) => return Some(Err(err))
             },
             None => return None
         };
-        let buf = transfer.actual().iter().cloned().collect();
+        let buf: Vec<u8> = transfer.actual().iter().cloned().collect();
         let endpoint_direction = transfer.endpoint();
+        trace!(target: consts::LOG_TRANSPORT, "Received on endpoint {:#04x}: {}", endpoint_direction, Hex(&buf));
         // don't resubmit control packets
         if endpoint_direction != 0x80 {
-            match self.usb_wrapper.as_mut().unwrap().async_group.submit(transfer) {
+            match self.wrapper().and_then(|w| w.async_group.submit(transfer)) {
                 Ok(_) => {},
                 Err(err) => return Some(Err(err))
             }
@@ -99,17 +255,57 @@ impl Handle {
         Some(Ok((endpoint_direction, buf)))
     }
 
-    pub fn listen_iface2(&mut self, timeout: Duration) -> UsbResult<()> {
-        let mut vec = Vec::new();
-        vec.resize(64, 0u8);
-        self.send_interrupt(0x82, vec, timeout)
+    fn pollfds(&self) -> Vec<(RawFd, i16)> {
+        self.usb_wrapper.as_ref().map(|w| w.pollfds()).unwrap_or(Vec::new())
     }
 
-    pub fn listen_iface1(&mut self, timeout: Duration) -> UsbResult<()> {
-        let mut vec = Vec::new();
-        vec.resize(8, 0u8);
-        try!(self.send_interrupt(0x81, vec, timeout));
-        Ok(())
+    fn bus_address(&self) -> Option<(u8, u8)> {
+        self.device_info().map(|info| (info.bus_number, info.address))
+    }
+
+    fn listening(&self) -> Vec<(u8, usize)> {
+        match self.mode {
+            ConnectionMode::Full => vec![(0x81, 8), (0x82, 64)],
+            ConnectionMode::LightingOnly => vec![(0x82, 64)],
+        }
+    }
+
+    fn model(&self) -> &'static DeviceModel {
+        self.model
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use libusb::Error as UsbError;
+    use device::{DeviceSelector, ConnectionMode};
+    use model;
+    use super::{ControlPacket, Handle, Transport};
+
+    // handle after a failed reconnect attempt
+    fn disconnected() -> Handle {
+        Handle {
+            usb_wrapper: None,
+            selector: DeviceSelector::Any,
+            mode: ConnectionMode::Full,
+            hotplug: None,
+            model: &model::G910,
+        }
+    }
+
+    #[test]
+    fn disconnected_handle_fails_with_no_device() {
+        let mut handle = disconnected();
+        let mut buf = vec![0x11, 0xff, 0x0f, 0x5b];
+        buf.resize(20, 0u8);
+        let packet = ControlPacket::new(buf, 0x80, 0x21, 9, 0x0212, 0x0001, Duration::from_secs(10));
+        assert_eq!(handle.send_control(packet), Err(UsbError::NoDevice));
+        assert_eq!(handle.send_interrupt(0x82, vec![0; 64], Duration::from_secs(1)), Err(UsbError::NoDevice));
+        match handle.recv(Duration::from_secs(0)) {
+            Some(Err(UsbError::NoDevice)) => {},
+            _ => panic!("expected NoDevice"),
+        }
+        assert!(handle.pollfds().is_empty());
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use libc;
+use libusb::{Result as UsbResult, Error as UsbError};
+use handle::{Transport, ControlPacket};
+use model::{self, DeviceModel};
+
+/// Transport talking to the keyboard through its `/dev/hidraw*` node.
+///
+/// Unlike `Handle`, this doesn't detach the kernel driver, so the keyboard keeps typing.
+/// Only the HID++ interface is opened, which is used for lighting.
+/// Key input is handled by the kernel, but media and rollover reports are
+/// still received, as hidraw gets a copy of every report of its interface.
+pub struct HidrawTransport {
+    reader: File,
+    writer: File,
+    model: &'static DeviceModel,
+    // set if opened by searching, used to find the keyboard again on reconnect
+    search: bool,
+    // writes complete immediately, but are returned by recv like libusb does
+    completed: VecDeque<(u8, Vec<u8>)>,
+}
+
+impl HidrawTransport {
+    /// Opens the HID++ interface of the first supported keyboard.
+    pub fn new() -> UsbResult<HidrawTransport> {
+        let (path, model) = match try!(find_nodes()).into_iter().next() {
+            Some(node) => node,
+            None => return Err(UsbError::NoDevice),
+        };
+        let mut transport = try!(HidrawTransport::open(path, model));
+        transport.search = true;
+        Ok(transport)
+    }
+
+    /// Opens given hidraw node, which must be the HID++ interface (interface 1) of a keyboard.
+    pub fn open<P: AsRef<Path>>(path: P, model: &'static DeviceModel) -> UsbResult<HidrawTransport> {
+        let file = try!(OpenOptions::new().read(true).write(true).open(path).map_err(from_io));
+        let writer = try!(file.try_clone().map_err(from_io));
+        Ok(HidrawTransport::from_files(file, writer, model))
+    }
+
+    /// Creates a transport reading input reports from `reader` and writing output reports to `writer`.
+    ///
+    /// Like a hidraw node, each read from `reader` must return exactly one report,
+    /// so for tests a `UnixDatagram` socket pair can be used instead of an actual device.
+    pub fn from_files(reader: File, writer: File, model: &'static DeviceModel) -> HidrawTransport {
+        HidrawTransport {
+            reader: reader,
+            writer: writer,
+            model: model,
+            search: false,
+            completed: VecDeque::new(),
+        }
+    }
+}
+
+impl Transport for HidrawTransport {
+    fn send_control(&mut self, packet: ControlPacket) -> UsbResult<()> {
+        // the packet starts with its report id, just like hidraw expects
+        try!(self.writer.write_all(packet.buf()).map_err(from_io));
+        self.completed.push_back((packet.endpoint_direction(), packet.buf().to_vec()));
+        Ok(())
+    }
+
+    fn send_interrupt(&mut self, _endpoint_direction: u8, _buf: Vec<u8>,
+                      _timeout: Duration) -> UsbResult<()> {
+        // hidraw delivers all input reports without having to listen for them
+        Ok(())
+    }
+
+    fn recv(&mut self, timeout: Duration) -> Option<UsbResult<(u8, Vec<u8>)>> {
+        match self.completed.pop_front() {
+            Some(completed) => return Some(Ok(completed)),
+            None => {}
+        }
+        let mut pollfd = libc::pollfd {
+            fd: self.reader.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let millis = timeout.as_secs().saturating_mul(1000) + (timeout.subsec_nanos() / 1_000_000) as u64;
+        let millis = if millis > i32::max_value() as u64 { -1 } else { millis as libc::c_int };
+        let res = unsafe { libc::poll(&mut pollfd, 1, millis) };
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EINTR) {
+                return None;
+            }
+            return Some(Err(from_io(err)));
+        } else if res == 0 {
+            return None;
+        }
+        if pollfd.revents & (libc::POLLERR | libc::POLLHUP) != 0 && pollfd.revents & libc::POLLIN == 0 {
+            return Some(Err(UsbError::NoDevice));
+        }
+        let mut buf = vec![0u8; 64];
+        match self.reader.read(&mut buf) {
+            // the other end of a test socket pair was closed
+            Ok(0) => Some(Err(UsbError::NoDevice)),
+            Ok(len) => {
+                buf.truncate(len);
+                // all reports of the HID++ interface arrive on endpoint 0x82
+                Some(Ok((0x82, buf)))
+            },
+            Err(e) => Some(Err(from_io(e))),
+        }
+    }
+
+    fn reconnect(&mut self) -> UsbResult<()> {
+        if !self.search {
+            return Err(UsbError::NoDevice);
+        }
+        // the node number may change when the keyboard is replugged
+        let model = self.model;
+        let path = match try!(find_nodes()).into_iter().find(|&(_, m)| m == model) {
+            Some((path, _)) => path,
+            None => return Err(UsbError::NoDevice),
+        };
+        let mut transport = try!(HidrawTransport::open(path, model));
+        transport.search = true;
+        *self = transport;
+        Ok(())
+    }
+
+    fn pollfds(&self) -> Vec<(RawFd, i16)> {
+        vec![(self.reader.as_raw_fd(), libc::POLLIN)]
+    }
+
+    fn model(&self) -> &'static DeviceModel {
+        self.model
+    }
+}
+
+/// Lists the HID++ hidraw nodes of all supported keyboards.
+pub fn find_nodes() -> UsbResult<Vec<(PathBuf, &'static DeviceModel)>> {
+    let mut res = Vec::new();
+    let entries = match fs::read_dir("/sys/class/hidraw") {
+        Ok(entries) => entries,
+        // hidraw isn't available
+        Err(_) => return Ok(res),
+    };
+    for entry in entries {
+        let entry = try!(entry.map_err(from_io));
+        let mut uevent = String::new();
+        match File::open(entry.path().join("device/uevent")).and_then(|mut f| f.read_to_string(&mut uevent)) {
+            Ok(_) => {},
+            Err(_) => continue,
+        }
+        match parse_uevent(&uevent) {
+            Some(model) => res.push((Path::new("/dev").join(entry.file_name()), model)),
+            None => {}
+        }
+    }
+    res.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(res)
+}
+
+/// Returns the model if the uevent belongs to interface 1 of a supported keyboard.
+fn parse_uevent(uevent: &str) -> Option<&'static DeviceModel> {
+    let mut model = None;
+    let mut interface1 = false;
+    for line in uevent.lines() {
+        if line.starts_with("HID_ID=") {
+            // HID_ID=<bus>:<vendor>:<product>
+            let ids: Vec<_> = line["HID_ID=".len()..].split(':')
+                .filter_map(|id| u32::from_str_radix(id, 16).ok())
+                .collect();
+            if ids.len() == 3 {
+                model = model::find(ids[1] as u16, ids[2] as u16);
+            }
+        } else if line.starts_with("HID_PHYS=") {
+            // HID_PHYS=usb-0000:00:14.0-2/input1
+            interface1 = line.ends_with("/input1");
+        }
+    }
+    if interface1 {
+        model
+    } else {
+        None
+    }
+}
+
+fn from_io(err: io::Error) -> UsbError {
+    match err.raw_os_error() {
+        Some(libc::ENODEV) | Some(libc::ENOENT) => UsbError::NoDevice,
+        Some(libc::EACCES) => UsbError::Access,
+        Some(libc::EPIPE) => UsbError::Pipe,
+        _ => UsbError::Io,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::ErrorKind;
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    use std::os::unix::net::UnixDatagram;
+    use color::{Color, ColorPacket, FlushPacket, KeyColor};
+    use handle::ToControlPacket;
+    use keys::{GamingKey, StandardKey};
+    use keyboard::{Keyboard, KeyboardImpl};
+    use model;
+    use native_effect::{NativeEffect, NativeEffectPacket, Zone};
+    use super::HidrawTransport;
+
+    // returns the keyboard and the device end of the socket pair
+    fn keyboard() -> (KeyboardImpl, UnixDatagram) {
+        let (keyboard_end, device) = UnixDatagram::pair().unwrap();
+        let reader = unsafe { File::from_raw_fd(keyboard_end.into_raw_fd()) };
+        let writer = reader.try_clone().unwrap();
+        let transport = HidrawTransport::from_files(reader, writer, &model::G910);
+        // a datagram is queued at the other end before send returns, so nothing has to be waited for
+        device.set_nonblocking(true).unwrap();
+        (KeyboardImpl::with_transport(Box::new(transport)), device)
+    }
+
+    // the next report written by the keyboard, None if it doesn't write one
+    fn written(device: &UnixDatagram) -> Option<Vec<u8>> {
+        let mut buf = [0u8; 64];
+        match device.recv(&mut buf) {
+            Ok(len) => Some(buf[..len].to_vec()),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => None,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    fn ack(device: &UnixDatagram, report: &[u8]) {
+        let mut ack = vec![0x11, report[1], report[2], report[3]];
+        ack.resize(20, 0u8);
+        device.send(&ack).unwrap();
+    }
+
+    // acknowledges every report written by the keyboard until it stops writing
+    fn exchange(keyboard: &mut KeyboardImpl, device: &UnixDatagram) -> Vec<Vec<u8>> {
+        let mut reports = Vec::new();
+        loop {
+            while keyboard.poll_events().unwrap().is_some() {}
+            let report = match written(device) {
+                Some(report) => report,
+                None => return reports,
+            };
+            ack(device, &report);
+            reports.push(report);
+        }
+    }
+
+    #[test]
+    fn set_key_colors_waits_for_ack() {
+        let (mut keyboard, device) = keyboard();
+        let red = Color::new(255, 0, 0);
+        keyboard.set_key_colors(vec![KeyColor::new(StandardKey::A, red)]).unwrap();
+        let mut color = ColorPacket::new(&model::G910);
+        color.add(StandardKey::A, red);
+        let report = written(&device).unwrap();
+        assert_eq!(report, color.to_control_packet().buf());
+        // the flush waits for the acknowledgement of the colors
+        while keyboard.poll_events().unwrap().is_some() {}
+        assert_eq!(written(&device), None);
+        ack(&device, &report);
+        assert_eq!(exchange(&mut keyboard, &device), vec![
+            FlushPacket::new(&model::G910).to_control_packet().buf().to_vec(),
+        ]);
+        let frame = keyboard.last_frame();
+        assert!(keyboard.frame_acknowledged(frame).unwrap().is_ok());
+    }
+
+    #[test]
+    fn set_all_colors() {
+        let (mut keyboard, device) = keyboard();
+        let blue = Color::new(0, 0, 255);
+        keyboard.set_all_colors(blue).unwrap();
+        let reports = exchange(&mut keyboard, &device);
+        assert_eq!(reports.len(), 4);
+        // zones with native effects are colored with a single packet
+        assert_eq!(reports[0], NativeEffectPacket::new(&model::G910, Zone::Keys, NativeEffect::Color(blue)).unwrap()
+            .to_control_packet().buf());
+        assert_eq!(reports[1], NativeEffectPacket::new(&model::G910, Zone::Logo, NativeEffect::Color(blue)).unwrap()
+            .to_control_packet().buf());
+        // G-keys are colored per key
+        let mut gaming = ColorPacket::new(&model::G910);
+        for key in GamingKey::values().into_iter().filter(|&k| k != GamingKey::None) {
+            gaming.add(key, blue);
+        }
+        assert_eq!(reports[2], gaming.to_control_packet().buf());
+        assert_eq!(reports[3], FlushPacket::new(&model::G910).to_control_packet().buf());
+    }
+}
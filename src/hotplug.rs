@@ -0,0 +1,231 @@
+use std::ptr;
+use std::cmp;
+use std::mem;
+use std::time::{Duration, Instant};
+use libc::{c_int, c_void, timeval};
+use libusb_sys as ffi;
+use libusb::{Result as UsbResult, Error as UsbError};
+use device::{DeviceInfo, DeviceSelector};
+
+// devices are referenced while they are stored here
+struct HotplugState {
+    // devices which arrived since they were last matched against the selector
+    arrived: Vec<*mut ffi::libusb_device>,
+    // the present device matching the selector
+    matched: Option<*mut ffi::libusb_device>,
+}
+
+/// Watches for arrival and departure of a device using libusb's hotplug callbacks.
+///
+/// The monitor uses its own libusb context, so it keeps working while the
+/// context of the actual device connection is torn down and recreated on reconnect.
+pub struct HotplugMonitor {
+    context: *mut ffi::libusb_context,
+    selector: DeviceSelector,
+    callback: ffi::libusb_hotplug_callback_handle,
+    // boxed as libusb holds a pointer to it
+    state: Box<HotplugState>,
+}
+
+impl HotplugMonitor {
+    /// Starts watching for devices with given ids which match given selector.
+    ///
+    /// Returns None if the platform doesn't support hotplug, in which case
+    /// callers must fall back to polling.
+    pub fn new(vendor_id: u16, product_id: u16, selector: DeviceSelector) -> Option<HotplugMonitor> {
+        if unsafe { ffi::libusb_has_capability(ffi::LIBUSB_CAP_HAS_HOTPLUG) } == 0 {
+            return None;
+        }
+        let mut context = ptr::null_mut();
+        if unsafe { ffi::libusb_init(&mut context) } != ffi::LIBUSB_SUCCESS {
+            return None;
+        }
+        let mut monitor = HotplugMonitor {
+            context: context,
+            selector: selector,
+            callback: 0,
+            state: Box::new(HotplugState {
+                arrived: Vec::new(),
+                matched: None,
+            }),
+        };
+        let res = unsafe {
+            ffi::libusb_hotplug_register_callback(
+                monitor.context,
+                ffi::LIBUSB_HOTPLUG_EVENT_DEVICE_ARRIVED | ffi::LIBUSB_HOTPLUG_EVENT_DEVICE_LEFT,
+                // get an arrival event for already connected devices
+                ffi::LIBUSB_HOTPLUG_ENUMERATE,
+                vendor_id as c_int,
+                product_id as c_int,
+                ffi::LIBUSB_HOTPLUG_MATCH_ANY,
+                hotplug_callback,
+                &mut *monitor.state as *mut HotplugState as *mut c_void,
+                &mut monitor.callback,
+            )
+        };
+        if res != ffi::LIBUSB_SUCCESS {
+            // context is freed by Drop, deregistering the null callback is a noop
+            return None;
+        }
+        Some(monitor)
+    }
+
+    /// Blocks until the device is present or the timeout elapses.
+    ///
+    /// If timeout is None, waits indefinitely.
+    /// Returns whether the device is present.
+    pub fn wait_for_device(&mut self, timeout: Option<Duration>) -> UsbResult<bool> {
+        let start = Instant::now();
+        // process pending events first, as the device may have left in the meantime
+        try!(self.handle_events(Duration::from_secs(0)));
+        self.match_arrived();
+        while self.state.matched.is_none() {
+            let remaining = match timeout {
+                Some(timeout) => {
+                    let elapsed = start.elapsed();
+                    if elapsed >= timeout {
+                        return Ok(false);
+                    }
+                    timeout - elapsed
+                },
+                None => Duration::from_secs(1),
+            };
+            // wake up at least every second, so we don't rely on libusb
+            // returning exactly when a callback was fired
+            try!(self.handle_events(cmp::min(remaining, Duration::from_secs(1))));
+            self.match_arrived();
+        }
+        Ok(true)
+    }
+
+    /// Forgets the present device, e.g. because opening it failed as it didn't match after all.
+    ///
+    /// The next `wait_for_device` waits until a matching device arrives.
+    pub fn device_unavailable(&mut self) {
+        match self.state.matched.take() {
+            Some(device) => unsafe { ffi::libusb_unref_device(device) },
+            None => {}
+        }
+    }
+
+    // checks the arrived devices against the selector outside of the callback,
+    // as reading the serial number must not be done within it
+    fn match_arrived(&mut self) {
+        let arrived = mem::replace(&mut self.state.arrived, Vec::new());
+        for device in arrived {
+            let matches = self.state.matched.is_none()
+                && unsafe { device_info(device, &self.selector) }
+                    .map_or(false, |info| self.selector.matches(&info));
+            if matches {
+                self.state.matched = Some(device);
+            } else {
+                unsafe { ffi::libusb_unref_device(device) };
+            }
+        }
+    }
+
+    fn handle_events(&mut self, timeout: Duration) -> UsbResult<()> {
+        let tv = timeval {
+            tv_sec: timeout.as_secs() as _,
+            tv_usec: (timeout.subsec_nanos() / 1000) as _,
+        };
+        let res = unsafe {
+            ffi::libusb_handle_events_timeout_completed(self.context, &tv, ptr::null_mut())
+        };
+        if res < 0 {
+            Err(from_libusb(res))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for HotplugMonitor {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::libusb_hotplug_deregister_callback(self.context, self.callback);
+            self.device_unavailable();
+            for device in self.state.arrived.drain(..) {
+                ffi::libusb_unref_device(device);
+            }
+            ffi::libusb_exit(self.context);
+        }
+    }
+}
+
+extern "C" fn hotplug_callback(_context: *mut ffi::libusb_context, device: *mut ffi::libusb_device,
+                               event: ffi::libusb_hotplug_event, user_data: *mut c_void) -> c_int {
+    let state = unsafe { &mut *(user_data as *mut HotplugState) };
+    if event == ffi::LIBUSB_HOTPLUG_EVENT_DEVICE_ARRIVED {
+        // keep the device alive until it's matched outside of the callback
+        state.arrived.push(unsafe { ffi::libusb_ref_device(device) });
+    } else if event == ffi::LIBUSB_HOTPLUG_EVENT_DEVICE_LEFT {
+        if state.matched == Some(device) {
+            state.matched = None;
+            unsafe { ffi::libusb_unref_device(device) };
+        }
+        match state.arrived.iter().position(|&d| d == device) {
+            Some(i) => unsafe { ffi::libusb_unref_device(state.arrived.remove(i)) },
+            None => {}
+        }
+    }
+    // keep the callback registered
+    0
+}
+
+// the serial number is only read if the selector needs it, as the device must be opened for it
+unsafe fn device_info(device: *mut ffi::libusb_device, selector: &DeviceSelector) -> Option<DeviceInfo> {
+    let mut descriptor: ffi::libusb_device_descriptor = mem::zeroed();
+    if ffi::libusb_get_device_descriptor(device, &mut descriptor) != ffi::LIBUSB_SUCCESS {
+        return None;
+    }
+    // USB 3.0 allows at most 7 tiers
+    let mut ports = [0u8; 7];
+    let len = ffi::libusb_get_port_numbers(device, ports.as_mut_ptr(), ports.len() as c_int);
+    let serial = match *selector {
+        DeviceSelector::Serial(_) => read_serial(device, descriptor.iSerialNumber),
+        _ => None,
+    };
+    Some(DeviceInfo {
+        product_id: descriptor.idProduct,
+        bus_number: ffi::libusb_get_bus_number(device),
+        address: ffi::libusb_get_device_address(device),
+        port_numbers: if len > 0 { ports[..len as usize].to_vec() } else { Vec::new() },
+        serial: serial,
+    })
+}
+
+unsafe fn read_serial(device: *mut ffi::libusb_device, index: u8) -> Option<String> {
+    if index == 0 {
+        return None;
+    }
+    let mut handle = ptr::null_mut();
+    if ffi::libusb_open(device, &mut handle) != ffi::LIBUSB_SUCCESS {
+        return None;
+    }
+    let mut buf = [0u8; 256];
+    let len = ffi::libusb_get_string_descriptor_ascii(handle, index, buf.as_mut_ptr(), buf.len() as c_int);
+    ffi::libusb_close(handle);
+    if len <= 0 {
+        return None;
+    }
+    String::from_utf8(buf[..len as usize].to_vec()).ok()
+}
+
+fn from_libusb(err: c_int) -> UsbError {
+    match err {
+        -1 => UsbError::Io,
+        -2 => UsbError::InvalidParam,
+        -3 => UsbError::Access,
+        -4 => UsbError::NoDevice,
+        -5 => UsbError::NotFound,
+        -6 => UsbError::Busy,
+        -7 => UsbError::Timeout,
+        -8 => UsbError::Overflow,
+        -9 => UsbError::Pipe,
+        -10 => UsbError::Interrupted,
+        -11 => UsbError::NoMem,
+        -12 => UsbError::NotSupported,
+        _ => UsbError::Other,
+    }
+}
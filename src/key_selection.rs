@@ -0,0 +1,304 @@
+use std::str::FromStr;
+use std::result::Result as StdResult;
+use keys::*;
+use model;
+use error::{Error, Result};
+
+/// Predefined group of keys, written as `@name` in key selections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyGroup {
+    /// F1 to F12.
+    Functions,
+    /// 1 to 0 above the letters.
+    Numbers,
+    /// A to Z.
+    Letters,
+    /// All keys of the numpad including NumLock.
+    Numpad,
+    Arrows,
+    /// Insert, Delete, Home, End, PageUp and PageDown.
+    Navigation,
+    /// Control, Shift, Alt and Windows keys on both sides.
+    Modifiers,
+    /// G1 to G9.
+    GamingKeys,
+    Logos,
+}
+
+impl KeyGroup {
+    pub fn values() -> Vec<KeyGroup> {
+        vec![KeyGroup::Functions, KeyGroup::Numbers, KeyGroup::Letters, KeyGroup::Numpad,
+            KeyGroup::Arrows, KeyGroup::Navigation, KeyGroup::Modifiers, KeyGroup::GamingKeys,
+            KeyGroup::Logos]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            KeyGroup::Functions => "functions",
+            KeyGroup::Numbers => "numbers",
+            KeyGroup::Letters => "letters",
+            KeyGroup::Numpad => "numpad",
+            KeyGroup::Arrows => "arrows",
+            KeyGroup::Navigation => "navigation",
+            KeyGroup::Modifiers => "modifiers",
+            KeyGroup::GamingKeys => "gkeys",
+            KeyGroup::Logos => "logos",
+        }
+    }
+
+    pub fn keys(&self) -> Vec<Key> {
+        use keys::StandardKey::*;
+        let standard = |range: (StandardKey, StandardKey)| -> Vec<Key> {
+            StandardKey::values().into_iter()
+                .filter(|&k| k as u8 >= range.0 as u8 && k as u8 <= range.1 as u8)
+                .map(Key::from)
+                .collect()
+        };
+        match *self {
+            KeyGroup::Functions => standard((F1, F12)),
+            KeyGroup::Numbers => standard((_1, _0)),
+            KeyGroup::Letters => standard((A, Y)),
+            KeyGroup::Numpad => StandardKey::values().into_iter()
+                .filter(|&k| model::is_numpad(k))
+                .map(Key::from)
+                .collect(),
+            KeyGroup::Arrows => vec![Up.into(), Left.into(), Down.into(), Right.into()],
+            KeyGroup::Navigation => standard((Insert, PageDown)),
+            KeyGroup::Modifiers => standard((LeftControl, RightWindows)),
+            KeyGroup::GamingKeys => GamingKey::values().into_iter()
+                .filter(|&g| g != GamingKey::None)
+                .map(Key::from)
+                .collect(),
+            KeyGroup::Logos => Logo::values().into_iter()
+                .filter(|&l| l != Logo::None)
+                .map(Key::from)
+                .collect(),
+        }
+    }
+}
+
+impl FromStr for KeyGroup {
+    type Err = String;
+
+    fn from_str(s: &str) -> StdResult<KeyGroup, String> {
+        KeyGroup::values().into_iter()
+            .find(|g| g.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                let names: Vec<_> = KeyGroup::values().iter().map(|g| g.name()).collect();
+                format!("valid groups: {}", names.join(" "))
+            })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Add,
+    Remove,
+}
+
+/// Parses a selection of keys, e.g. from a config file.
+///
+/// A selection is a list of terms separated by `,` or `+`, which add the keys of the term,
+/// or by ` - `, which removes them. Terms are evaluated from left to right.
+/// A term is either
+///
+/// * a key name like `W` or `G1`, case insensitive as in `StandardKey::from_str`,
+///   digits can be written as `1` or `_1`,
+/// * a range like `F1-F12` of keys of the same kind in the order of their codes, or
+/// * a group like `@numpad`, see `KeyGroup`.
+///
+/// The `-` of a range must not be surrounded by whitespace, while the `-` removing keys must.
+/// `G` is the standard key, the logo is only part of `@logos`.
+///
+/// Digits are ordered like on the keyboard, so `1-0` selects all of them.
+///
+/// Examples: `F1-F12`, `1-5`, `@numpad`, `W,A,S,D`, `@letters - Q`.
+/// The returned keys are in order of their first selection and contain no duplicates.
+pub fn parse_keys(selection: &str) -> Result<Vec<Key>> {
+    let mut keys: Vec<Key> = Vec::new();
+    // operator waiting for its term
+    let mut op = Some(Op::Add);
+    for token in tokenize(selection) {
+        let operator = match token {
+            "," | "+" => Some(Op::Add),
+            "-" => Some(Op::Remove),
+            _ => None,
+        };
+        match (operator, op) {
+            (Some(operator), None) => op = Some(operator),
+            (Some(_), Some(_)) => return Err(invalid(selection, &format!("missing key before {:?}", token))),
+            (None, Some(Op::Add)) => {
+                for key in try!(parse_term(selection, token)) {
+                    if !keys.contains(&key) {
+                        keys.push(key);
+                    }
+                }
+                op = None;
+            },
+            (None, Some(Op::Remove)) => {
+                let removed = try!(parse_term(selection, token));
+                keys.retain(|k| !removed.contains(k));
+                op = None;
+            },
+            (None, None) => return Err(invalid(selection, &format!("missing operator before {:?}", token))),
+        }
+    }
+    if op.is_some() {
+        return Err(invalid(selection, "missing key at the end"));
+    }
+    Ok(keys)
+}
+
+// splits into terms and operators, a `-` within a term is part of a range
+fn tokenize(selection: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (i, c) in selection.char_indices() {
+        if c.is_whitespace() || c == ',' || c == '+' {
+            match start.take() {
+                Some(s) => tokens.push(&selection[s..i]),
+                None => {}
+            }
+            if !c.is_whitespace() {
+                tokens.push(&selection[i..i + 1]);
+            }
+        } else if start.is_none() {
+            start = Some(i);
+        }
+    }
+    match start {
+        Some(s) => tokens.push(&selection[s..]),
+        None => {}
+    }
+    tokens
+}
+
+fn parse_term(selection: &str, term: &str) -> Result<Vec<Key>> {
+    if term.starts_with('@') {
+        return term[1..].parse::<KeyGroup>()
+            .map(|g| g.keys())
+            .map_err(|e| invalid(selection, &format!("unknown group {:?}, {}", term, e)));
+    }
+    let mut range = term.splitn(2, '-');
+    let first = try!(parse_key(selection, range.next().unwrap()));
+    let last = match range.next() {
+        Some(last) => try!(parse_key(selection, last)),
+        None => return Ok(vec![first]),
+    };
+    let (low, high): (u8, u8) = (first.clone().into(), last.clone().into());
+    let keys: Vec<Key> = match (first, last) {
+        (Key::Standard(_), Key::Standard(_)) => StandardKey::values().into_iter().map(Key::from).collect(),
+        (Key::Gaming(_), Key::Gaming(_)) => GamingKey::values().into_iter().map(Key::from).collect(),
+        _ => return Err(invalid(selection, &format!("range {:?} spans different kinds of keys", term))),
+    };
+    let keys: Vec<_> = keys.into_iter()
+        .filter(|k| {
+            let value: u8 = k.clone().into();
+            value >= low && value <= high
+        })
+        .collect();
+    if keys.len() == 0 {
+        return Err(invalid(selection, &format!("range {:?} is empty", term)));
+    }
+    Ok(keys)
+}
+
+fn parse_key(selection: &str, name: &str) -> Result<Key> {
+    // digit keys are named `_1` to `_0`
+    let name = if name.len() == 1 && name.chars().all(|c| c.is_ascii_digit()) {
+        format!("_{}", name)
+    } else {
+        name.to_string()
+    };
+    let key = match StandardKey::from_str(&name) {
+        Ok(key) => Key::from(key),
+        Err(_) => match GamingKey::from_str(&name) {
+            Ok(key) => Key::from(key),
+            Err(_) => return Err(invalid(selection, &format!("unknown key {:?}", name))),
+        }
+    };
+    let value: u8 = key.clone().into();
+    if value == 0 {
+        return Err(invalid(selection, &format!("unknown key {:?}", name)));
+    }
+    Ok(key)
+}
+
+fn invalid(selection: &str, reason: &str) -> Error {
+    Error::InvalidKeySelection(format!("{:?}: {}", selection, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use keys::*;
+    use keys::StandardKey::*;
+    use error::Error;
+    use super::{parse_keys, KeyGroup};
+
+    fn standard(keys: &[StandardKey]) -> Vec<Key> {
+        keys.iter().map(|&k| Key::from(k)).collect()
+    }
+
+    #[test]
+    fn valid_selections() {
+        let tests = vec![
+            ("W", standard(&[W])),
+            ("w,a , s+d", standard(&[W, A, S, D])),
+            ("F1-F4", standard(&[F1, F2, F3, F4])),
+            ("1-5", standard(&[_1, _2, _3, _4, _5])),
+            ("_1-_3", standard(&[_1, _2, _3])),
+            ("1-0", standard(&[_1, _2, _3, _4, _5, _6, _7, _8, _9, _0])),
+            ("1, 0", standard(&[_1, _0])),
+            ("F1-F1", standard(&[F1])),
+            // ranges follow the key codes, not the rows
+            ("X-_2", standard(&[X, Z, Y, _1, _2])),
+            ("@arrows", standard(&[Up, Left, Down, Right])),
+            ("@ARROWS - Left", standard(&[Up, Down, Right])),
+            ("@letters - A-W - Z", standard(&[X, Y])),
+            ("A, @arrows, A", standard(&[A, Up, Left, Down, Right])),
+            ("G1-G3", vec![GamingKey::G1.into(), GamingKey::G2.into(), GamingKey::G3.into()]),
+            ("G, G1", vec![StandardKey::G.into(), GamingKey::G1.into()]),
+        ];
+        for (selection, expected) in tests {
+            assert_eq!(parse_keys(selection).unwrap(), expected, "{:?}", selection);
+        }
+    }
+
+    #[test]
+    fn invalid_selections() {
+        let tests = vec![
+            ("Foo", "\"Foo\": unknown key \"Foo\""),
+            ("12", "\"12\": unknown key \"12\""),
+            ("None", "\"None\": unknown key \"None\""),
+            ("@foo", "\"@foo\": unknown group \"@foo\", valid groups: functions numbers letters \
+                      numpad arrows navigation modifiers gkeys logos"),
+            ("F12-F1", "\"F12-F1\": range \"F12-F1\" is empty"),
+            ("F1-G1", "\"F1-G1\": range \"F1-G1\" spans different kinds of keys"),
+            ("A-", "\"A-\": unknown key \"\""),
+            ("A,,B", "\"A,,B\": missing key before \",\""),
+            (", A", "\", A\": missing key before \",\""),
+            ("A B", "\"A B\": missing operator before \"B\""),
+            ("A -", "\"A -\": missing key at the end"),
+            ("", "\"\": missing key at the end"),
+        ];
+        for (selection, reason) in tests {
+            match parse_keys(selection) {
+                Err(Error::InvalidKeySelection(ref r)) if r == reason => {},
+                res => panic!("{:?}: unexpected result {:?}", selection, res),
+            }
+        }
+    }
+
+    #[test]
+    fn groups() {
+        for group in KeyGroup::values() {
+            assert_eq!(group.name().parse::<KeyGroup>(), Ok(group));
+            assert!(group.keys().len() > 0, "{}", group.name());
+        }
+        assert_eq!(KeyGroup::Functions.keys().len(), 12);
+        assert_eq!(KeyGroup::Numbers.keys().len(), 10);
+        assert_eq!(KeyGroup::Letters.keys().len(), 26);
+        assert_eq!(KeyGroup::GamingKeys.keys().len(), 9);
+        assert!(!KeyGroup::Numpad.keys().contains(&Key::from(_1)));
+    }
+}
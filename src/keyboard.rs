@@ -1,104 +1,464 @@
 use std::collections::{HashMap, VecDeque};
-use std::time::Duration;
-use libusb::{Result as UsbResult, Error as UsbError};
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, Instant};
+use libusb::Error as UsbError;
+use error::{Error, Result};
+use libc;
 use nix::sys::signal::{SigAction, sigaction, SaFlags, SigSet, SigHandler, SIGINT, SIGTERM};
+use nix::fcntl::{fcntl, FcntlArg, O_NONBLOCK};
+use nix::unistd;
 use nix::Result as NixResult;
-use handle::{Handle, ControlPacket, ToControlPacket};
+use handle::{Handle, Transport, ControlPacket, ToControlPacket};
 use color::*;
+use native_effect::{NativeEffect, NativeEffectPacket, Zone};
 use keys::*;
 use parser::*;
-use event::{GenericHandler, Handler};
+use event::{GenericHandler, Handler, KeySink, KeyEvent};
+use device::{DeviceSelector, ConnectionMode};
+use model::{DeviceModel, MODELS};
+use consts;
+use utils::Hex;
+use reconnect::ReconnectPolicy;
+use remote::{Remote, Command, StopToken};
+#[cfg(target_os = "linux")]
+use hidraw::HidrawTransport;
+#[cfg(target_os = "linux")]
+use uinput::UinputSink;
 
 pub trait Keyboard {
-    fn set_key_colors(&mut self, key_colors: Vec<KeyColor>) -> UsbResult<()>;
-    fn set_color(&mut self, key_color: KeyColor) -> UsbResult<()>;
-    fn set_all_colors(&mut self, color: Color) -> UsbResult<()>;
+    fn set_key_colors(&mut self, key_colors: Vec<KeyColor>) -> Result<()>;
+    fn set_color(&mut self, key_color: KeyColor) -> Result<()>;
+    fn set_all_colors(&mut self, color: Color) -> Result<()>;
+    fn set_zone_color(&mut self, zone: Zone, color: Color) -> Result<()>;
+    fn set_brightness(&mut self, percent: u8) -> Result<()>;
+    fn set_native_effect(&mut self, zone: Zone, effect: NativeEffect) -> Result<()>;
+    fn get_color(&self, key: &Key) -> Option<Color>;
+    fn snapshot(&self) -> HashMap<Key, Color>;
+    fn model(&self) -> &'static DeviceModel;
+    fn set_reconnect_policy(&mut self, policy: ReconnectPolicy);
     fn set_reconnect_interval(&mut self, interval: Duration);
     fn set_reconnect_attempts(&mut self, attempts: i32);
     fn set_auto_reconnect(&mut self, enabled: bool);
-    fn reconnect(&mut self) -> UsbResult<()>;
+    fn set_ack_timeout(&mut self, timeout: Duration);
+    fn set_ack_retries(&mut self, retries: u32);
+    fn reconnect(&mut self) -> Result<()>;
     unsafe fn enable_signal_handling(&mut self) -> NixResult<()>;
     fn disable_signal_handling(&mut self) -> NixResult<()>;
 }
 
+// control packet waiting for its acknowledgement
+struct InFlight {
+    packet: ControlPacket,
+    deadline: Instant,
+    retries: u32,
+}
+
 pub struct KeyboardInternal {
-    handle: Handle,
+    handle: Box<Transport>,
     control_packet_queue: VecDeque<ControlPacket>,
-    sending_control: bool,
-    reconnect_interval: Duration,
-    reconnect_attempts: i32,
+    in_flight: Option<InFlight>,
+    ack_timeout: Duration,
+    ack_retries: u32,
+    // header (bytes 1 to 3) of the last acknowledged packet and the number of
+    // acknowledgements of its resends which may still arrive
+    late_acks: (Vec<u8>, u32),
+    // framebuffer with the last color set for each key, restored after reconnecting
+    colors: HashMap<Key, Color>,
+    // the keyboard's colors are unknown after an ack timeout, so all are resent with the next frame
+    resync_colors: bool,
+    // native effect running in each zone, restored after reconnecting
+    effects: HashMap<Zone, NativeEffect>,
+    // brightness in percent, colors in the framebuffer are undimmed
+    brightness: u8,
+    // colors set while the control packet queue was busy, sent as one frame once it's idle
+    pending_colors: HashMap<Key, Color>,
+    // every set_key_colors call is a frame, numbered starting at 1
+    frames_requested: u64,
+    frames_sent: u64,
+    frames_acknowledged: u64,
+    // ranges of frames dropped after an ack timeout, from after the first up to the second
+    frames_dropped: Vec<(u64, u64)>,
+    reconnect_policy: ReconnectPolicy,
+    // number of the last reconnect attempt, starting at 1
+    reconnect_attempt: u32,
     auto_reconnect: bool,
+    on_disconnected: Option<Box<FnMut(&Error)>>,
+    on_reconnecting: Option<Box<FnMut(u32)>>,
+    on_reconnected: Option<Box<FnMut()>>,
+    // (read, write) end of the pipe signals are written to
+    signal_pipe: Option<(RawFd, RawFd)>,
+    // a signal was received, reset once the handle loop stopped
+    signalled: bool,
+    stop_token: Option<StopToken>,
 }
 
 impl KeyboardInternal {
-    pub fn new() -> UsbResult<KeyboardInternal> {
-        let handle = try!(Handle::new());
-        Ok(KeyboardInternal {
-            handle: handle,
+    pub fn new() -> Result<KeyboardInternal> {
+        KeyboardInternal::open(DeviceSelector::Any, ConnectionMode::Full)
+    }
+
+    pub fn open(selector: DeviceSelector, mode: ConnectionMode) -> Result<KeyboardInternal> {
+        let handle = try!(Handle::open_with_mode(selector, mode).map_err(not_found));
+        Ok(KeyboardInternal::with_transport(Box::new(handle)))
+    }
+
+    pub fn with_transport(transport: Box<Transport>) -> KeyboardInternal {
+        KeyboardInternal {
+            handle: transport,
             control_packet_queue: VecDeque::new(),
-            sending_control: false,
-            reconnect_interval: Duration::from_secs(1),
-            reconnect_attempts: 10,
+            in_flight: None,
+            ack_timeout: Duration::from_millis(500),
+            ack_retries: 3,
+            late_acks: (Vec::new(), 0),
+            colors: HashMap::new(),
+            resync_colors: false,
+            effects: HashMap::new(),
+            brightness: 100,
+            pending_colors: HashMap::new(),
+            frames_requested: 0,
+            frames_sent: 0,
+            frames_acknowledged: 0,
+            frames_dropped: Vec::new(),
+            reconnect_policy: ReconnectPolicy::default(),
+            reconnect_attempt: 0,
             auto_reconnect: true,
-        })
+            on_disconnected: None,
+            on_reconnecting: None,
+            on_reconnected: None,
+            signal_pipe: None,
+            signalled: false,
+            stop_token: None,
+        }
+    }
+
+    /// Called with the error when the handle loop loses the connection to the keyboard.
+    pub fn on_disconnected<F: 'static + FnMut(&Error)>(&mut self, f: F) {
+        self.on_disconnected = Some(Box::new(f));
+    }
+
+    /// Called with the attempt number (starting at 1) before each reconnect attempt.
+    pub fn on_reconnecting<F: 'static + FnMut(u32)>(&mut self, f: F) {
+        self.on_reconnecting = Some(Box::new(f));
     }
 
-    pub fn queue_control_packet(&mut self, packet: ControlPacket) -> UsbResult<()> {
+    /// Called after the keyboard has been reconnected successfully.
+    pub fn on_reconnected<F: 'static + FnMut()>(&mut self, f: F) {
+        self.on_reconnected = Some(Box::new(f));
+    }
+
+    /// Reconnects to the keyboard according to the reconnect policy.
+    ///
+    /// While waiting for the keyboard or for the next attempt, `stop` is called every 50ms.
+    /// Returns false without reconnecting once it returns true.
+    fn reconnect_until(&mut self, stop: &mut FnMut(&mut KeyboardInternal) -> Result<bool>) -> Result<bool> {
+        self.begin_reconnect();
+        let poll_interval = Duration::from_millis(COMMAND_POLL_INTERVAL_MS);
+        loop {
+            if try!(stop(self)) {
+                return Ok(false);
+            }
+            let next_step = match try!(self.reconnect_step()) {
+                Some(delay) => Instant::now() + delay,
+                None => return Ok(true),
+            };
+            loop {
+                let now = Instant::now();
+                if now >= next_step {
+                    break;
+                }
+                ::std::thread::sleep(::std::cmp::min(next_step - now, poll_interval));
+                if try!(stop(self)) {
+                    return Ok(false);
+                }
+            }
+        }
+    }
+
+    /// Starts reconnecting, the attempts are made by `reconnect_step`.
+    fn begin_reconnect(&mut self) {
+        info!(target: consts::LOG_RECONNECT, "Starting reconnect");
+        // packets of the old connection won't be acknowledged anymore
+        self.reset_control_queue();
+        self.reconnect_attempt = 0;
+    }
+
+    /// Makes the next reconnect attempt, unless the transport supports hotplug
+    /// and the keyboard isn't plugged in.
+    ///
+    /// Returns None once reconnected, otherwise the time to wait before the next step.
+    /// Fails once the reconnect policy has no attempts left.
+    fn reconnect_step(&mut self) -> Result<Option<Duration>> {
+        // without hotplug support we just poll
+        match self.handle.wait_for_device(Some(Duration::from_secs(0))) {
+            Some(Ok(false)) => return Ok(Some(Duration::from_millis(COMMAND_POLL_INTERVAL_MS))),
+            Some(Err(e)) => return Err(e.into()),
+            _ => {}
+        }
+        self.reconnect_attempt += 1;
+        let attempt = self.reconnect_attempt;
+        match self.on_reconnecting {
+            Some(ref mut f) => f(attempt),
+            None => {}
+        }
+        let err = match self.handle.reconnect() {
+            Ok(_) => {
+                info!(target: consts::LOG_RECONNECT, "Reconnected after {} attempts", attempt);
+                // drop packets which failed to send while waiting
+                self.reset_control_queue();
+                try!(self.restore_colors());
+                match self.on_reconnected {
+                    Some(ref mut f) => f(),
+                    None => {}
+                }
+                return Ok(None);
+            },
+            Err(e) => e
+        };
+        warn!(target: consts::LOG_RECONNECT, "Reconnect attempt {} failed: {}", attempt, err);
+        match self.reconnect_policy.max_attempts() {
+            Some(max) if attempt >= max => return Err(err.into()),
+            _ => {}
+        }
+        Ok(Some(self.reconnect_policy.delay(attempt)))
+    }
+
+    fn disconnected(&mut self, err: &Error) {
+        warn!(target: consts::LOG_RECONNECT, "Connection lost: {}", err);
+        match self.on_disconnected {
+            Some(ref mut f) => f(err),
+            None => {}
+        }
+    }
+
+    pub fn queue_control_packet(&mut self, packet: ControlPacket) -> Result<()> {
+        // don't queue behind a packet which will never be acknowledged
+        try!(self.check_ack_timeout());
         self.control_packet_queue.push_back(packet);
-        if !self.sending_control {
+        if self.in_flight.is_none() {
             self.send_next_control()
         } else {
             Ok(())
         }
     }
 
-    pub fn send_next_control(&mut self) -> UsbResult<()> {
-        if self.control_packet_queue.len() == 0 {
-            self.sending_control = false;
+    pub fn send_next_control(&mut self) -> Result<()> {
+        let packet = match self.control_packet_queue.pop_front() {
+            Some(packet) => packet,
+            None => {
+                // all packets of the last sent frame have been acknowledged
+                self.in_flight = None;
+                self.frames_acknowledged = self.frames_sent;
+                return self.send_pending_colors();
+            }
+        };
+        self.in_flight = Some(InFlight {
+            packet: packet.clone(),
+            deadline: Instant::now() + self.ack_timeout,
+            retries: 0,
+        });
+        self.handle.send_control(packet).map_err(Error::from)
+    }
+
+    /// Called with each acknowledgement received from the keyboard, sends the next control packet.
+    ///
+    /// The acknowledgement echoes bytes 1 to 3 of the header of the acknowledged packet,
+    /// which consecutive packets of the same kind share, e.g. the color packets of a frame.
+    /// A resent packet may be acknowledged once per send, so once it has been acknowledged,
+    /// as many acknowledgements with its header as it has been resent are ignored.
+    /// The keyboard acknowledges in order, so these arrive before the one of the next packet.
+    /// If one of them got lost, the next packet with the same header is resent after its ack timeout.
+    /// Acknowledgements not matching the packet in flight are ignored as well.
+    pub fn control_acknowledged(&mut self, ack: &[u8]) -> Result<()> {
+        if ack.len() < 4 {
+            debug!(target: consts::LOG_TRANSPORT, "Ignoring malformed acknowledgement: {}", Hex(ack));
             return Ok(());
         }
-        if !self.sending_control {
-            self.sending_control = true;
+        if self.late_acks.1 > 0 && ack[1..4] == self.late_acks.0[..] {
+            self.late_acks.1 -= 1;
+            debug!(target: consts::LOG_TRANSPORT, "Ignoring late acknowledgement of a resent packet: {}", Hex(ack));
+            return Ok(());
         }
-        self.handle.send_control(self.control_packet_queue.pop_front().unwrap())
+        let retries = match self.in_flight {
+            Some(ref in_flight) if in_flight.packet.buf().len() >= 4
+                && ack[1..4] == in_flight.packet.buf()[1..4] => in_flight.retries,
+            _ => {
+                debug!(target: consts::LOG_TRANSPORT, "Ignoring acknowledgement not matching the packet in flight: {}", Hex(ack));
+                return Ok(());
+            }
+        };
+        self.late_acks = (ack[1..4].to_vec(), retries);
+        self.in_flight = None;
+        self.send_next_control()
     }
 
-    fn send_color<T: KeyType>(&mut self, color_packet: ColorPacket<T>) -> UsbResult<()> {
-        self.queue_control_packet(color_packet.to_control_packet())
+    /// Resends the control packet waiting for its acknowledgement if its deadline has passed.
+    ///
+    /// Once all retries are used up, all queued packets are dropped and `Error::AckTimeout` is returned.
+    pub fn check_ack_timeout(&mut self) -> Result<()> {
+        let expired = match self.in_flight {
+            Some(ref in_flight) => Instant::now() >= in_flight.deadline,
+            None => false,
+        };
+        if !expired {
+            return Ok(());
+        }
+        let mut in_flight = self.in_flight.take().unwrap();
+        if in_flight.retries >= self.ack_retries {
+            error!(target: consts::LOG_TRANSPORT, "Control packet not acknowledged after {} retries: {}",
+                   in_flight.retries, Hex(in_flight.packet.buf()));
+            self.drop_frames();
+            self.reset_control_queue();
+            self.resync_colors = true;
+            return Err(Error::AckTimeout);
+        }
+        in_flight.retries += 1;
+        debug!(target: consts::LOG_TRANSPORT, "Resending unacknowledged control packet (retry {}): {}",
+               in_flight.retries, Hex(in_flight.packet.buf()));
+        in_flight.deadline = Instant::now() + self.ack_timeout;
+        let packet = in_flight.packet.clone();
+        self.in_flight = Some(in_flight);
+        self.handle.send_control(packet).map_err(Error::from)
     }
 
-    fn flush_color(&mut self) -> UsbResult<()> {
-        self.queue_control_packet(FlushPacket::new().to_control_packet())
+    /// Marks all frames which haven't been acknowledged yet as dropped.
+    fn drop_frames(&mut self) {
+        let (first, last) = (self.frames_acknowledged, self.frames_requested);
+        match self.frames_dropped.last_mut() {
+            // nothing has been acknowledged since the last drop
+            Some(range) if range.1 >= first => {
+                range.1 = last;
+                return;
+            },
+            _ => {}
+        }
+        self.frames_dropped.push((first, last));
     }
-}
 
-impl Keyboard for KeyboardInternal {
-    fn set_key_colors(&mut self, key_colors: Vec<KeyColor>) -> UsbResult<()> {
-        let mut standard_packet = ColorPacket::new();
-        let mut gaming_packet = ColorPacket::new();
-        let mut logo_packet = ColorPacket::new();
+    /// Time left until the deadline of the control packet waiting for its acknowledgement.
+    fn ack_time_left(&self) -> Option<Duration> {
+        self.in_flight.as_ref().map(|in_flight| {
+            let now = Instant::now();
+            if in_flight.deadline > now {
+                in_flight.deadline - now
+            } else {
+                Duration::from_secs(0)
+            }
+        })
+    }
 
-        for key_color in key_colors {
-            match key_color.key {
+    /// Drops all queued and unacknowledged control packets and all pending colors.
+    fn reset_control_queue(&mut self) {
+        self.control_packet_queue.clear();
+        self.in_flight = None;
+        self.late_acks = (Vec::new(), 0);
+        self.pending_colors.clear();
+    }
+
+    /// Returns the number of the last frame, i.e. the last `set_key_colors` call which changed colors.
+    pub fn last_frame(&self) -> u64 {
+        self.frames_requested
+    }
+
+    /// Returns whether SIGINT or SIGTERM was received since the handle loop last stopped.
+    pub fn signal_received(&mut self) -> bool {
+        let read = match self.signal_pipe {
+            Some((read, _)) => read,
+            None => return self.signalled,
+        };
+        let mut buf = [0; 16];
+        // the pipe is non-blocking, so this stops once it's empty
+        while let Ok(len) = unistd::read(read, &mut buf) {
+            if len == 0 {
+                break;
+            }
+            self.signalled = true;
+        }
+        self.signalled
+    }
+
+    /// Returns whether the stop token was stopped or a signal was received.
+    fn stop_requested(&mut self) -> bool {
+        let stopped = self.stop_token.as_ref().map_or(false, |t| t.is_stopped());
+        self.signal_received() || stopped
+    }
+
+    /// Forgets the stop request, so the handle loop can be started again.
+    fn reset_stop(&mut self) {
+        self.signalled = false;
+        match self.stop_token {
+            Some(ref token) => token.reset(),
+            None => {}
+        }
+    }
+
+    fn close_signal_pipe(&mut self) {
+        match self.signal_pipe.take() {
+            Some((read, write)) => {
+                // signals may already be forwarded to another keyboard
+                let _ = SIGNAL_PIPE.compare_exchange(write as isize, -1, Ordering::SeqCst, Ordering::SeqCst);
+                let _ = unistd::close(read);
+                let _ = unistd::close(write);
+            },
+            None => {}
+        }
+    }
+
+    /// Returns whether all packets of given frame have been acknowledged by the keyboard.
+    ///
+    /// Returns None while the frame is still being sent and `Error::AckTimeout` if it was dropped
+    /// because the keyboard didn't acknowledge it.
+    pub fn frame_acknowledged(&self, frame: u64) -> Option<Result<()>> {
+        if self.frames_dropped.iter().any(|&(first, last)| frame > first && frame <= last) {
+            Some(Err(Error::AckTimeout))
+        } else if frame <= self.frames_acknowledged {
+            Some(Ok(()))
+        } else {
+            None
+        }
+    }
+
+    /// Sends all pending colors followed by a flush.
+    ///
+    /// Only called while the control packet queue is idle, so at most one frame is queued.
+    fn send_pending_colors(&mut self) -> Result<()> {
+        if self.pending_colors.len() == 0 {
+            return Ok(());
+        }
+        let model = self.handle.model();
+        let mut standard_packet = ColorPacket::new(model);
+        let mut gaming_packet = ColorPacket::new(model);
+        let mut logo_packet = ColorPacket::new(model);
+
+        self.frames_sent = self.frames_requested;
+        let mut key_colors: Vec<_> = self.pending_colors.drain().collect();
+        // packed by key id, so the packets don't depend on the map's iteration order
+        key_colors.sort_by_key(|&(ref key, _)| Into::<u8>::into(key.clone()));
+        let brightness = self.brightness;
+        for (key, color) in key_colors {
+            let color = color.scale(brightness);
+            match key {
                 Key::Standard(s) => {
-                    match standard_packet.add(s, key_color.color) {
+                    match standard_packet.add(s, color) {
                         Some(p) => try!(self.send_color(p)),
                         None => {}
                     }
                 },
                 Key::Gaming(g) => {
-                    match gaming_packet.add(g, key_color.color) {
+                    match gaming_packet.add(g, color) {
                         Some(p) => try!(self.send_color(p)),
                         None => {}
                     }
                 },
                 Key::Logo(l) => {
-                    match logo_packet.add(l, key_color.color) {
+                    match logo_packet.add(l, color) {
                         Some(p) => try!(self.send_color(p)),
                         None => {}
                     }
                 },
-                Key::Media(_) => return Err(UsbError::InvalidParam)
+                Key::Media(_) => return Err(Error::UnsupportedKey(key))
             }
         }
         if standard_packet.len() > 0 {
@@ -113,102 +473,400 @@ impl Keyboard for KeyboardInternal {
         self.flush_color()
     }
 
-    fn set_color(&mut self, key_color: KeyColor) -> UsbResult<()> {
+    fn send_color<T: KeyType>(&mut self, color_packet: ColorPacket<T>) -> Result<()> {
+        self.queue_control_packet(color_packet.to_control_packet())
+    }
+
+    fn flush_color(&mut self) -> Result<()> {
+        let model = self.handle.model();
+        self.queue_control_packet(FlushPacket::new(model).to_control_packet())
+    }
+
+    /// Resends the last set color of every key.
+    ///
+    /// The keyboard is reset when connecting, which resets all colors.
+    fn restore_colors(&mut self) -> Result<()> {
+        self.resync_colors = false;
+        try!(self.send_effects());
+        if self.colors.len() == 0 {
+            return Ok(());
+        }
+        for (key, color) in self.colors.iter() {
+            self.pending_colors.insert(key.clone(), color.clone());
+        }
+        self.frames_requested += 1;
+        self.send_if_idle()
+    }
+
+    fn send_effects(&mut self) -> Result<()> {
+        let mut effects: Vec<_> = self.effects.iter().map(|(z, e)| (*z, *e)).collect();
+        effects.sort_by_key(|&(zone, _)| zone.effect_id());
+        for (zone, effect) in effects {
+            try!(self.send_effect(zone, effect));
+        }
+        Ok(())
+    }
+
+    fn send_effect(&mut self, zone: Zone, effect: NativeEffect) -> Result<()> {
+        let model = self.handle.model();
+        let effect = effect.scale(self.brightness);
+        let packet = try!(NativeEffectPacket::new(model, zone, effect));
+        self.queue_control_packet(packet.to_control_packet())
+    }
+
+    /// Resends all effects and colors after a dropped frame.
+    ///
+    /// Returns whether colors have been added to the pending colors.
+    fn resync(&mut self) -> Result<bool> {
+        if !self.resync_colors {
+            return Ok(false);
+        }
+        self.resync_colors = false;
+        try!(self.send_effects());
+        for (key, color) in self.colors.iter() {
+            self.pending_colors.insert(key.clone(), color.clone());
+        }
+        Ok(self.colors.len() > 0)
+    }
+
+    /// Sends the pending colors unless the keyboard is still busy with the last frame.
+    fn send_if_idle(&mut self) -> Result<()> {
+        if self.in_flight.is_none() && self.control_packet_queue.len() == 0 {
+            self.send_pending_colors()
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Keyboard for KeyboardInternal {
+    /// Sets the colors of given keys.
+    ///
+    /// Only keys whose color differs from the framebuffer are sent.
+    /// If the keyboard is still busy with a previous frame, the colors are merged
+    /// into the next frame, replacing older colors of the same keys.
+    ///
+    /// Returns `UnsupportedKey` for media keys, which can't be lit,
+    /// and for keys the connected model doesn't have.
+    fn set_key_colors(&mut self, key_colors: Vec<KeyColor>) -> Result<()> {
+        let model = self.handle.model();
+        for key_color in key_colors.iter() {
+            match key_color.key {
+                Key::Media(_) => return Err(Error::UnsupportedKey(key_color.key.clone())),
+                ref k if !model.supports(k) => return Err(Error::UnsupportedKey(k.clone())),
+                _ => {}
+            }
+        }
+        let mut changed = try!(self.resync());
+        // remember colors even if sending fails, so they are restored on reconnect
+        for key_color in key_colors {
+            if self.colors.get(&key_color.key) == Some(&key_color.color) {
+                continue;
+            }
+            changed = true;
+            // per-key colors replace the native effect of the zone
+            match Zone::of(&key_color.key) {
+                Some(zone) => { self.effects.remove(&zone); },
+                None => {}
+            }
+            self.colors.insert(key_color.key.clone(), key_color.color.clone());
+            self.pending_colors.insert(key_color.key, key_color.color);
+        }
+        if !changed {
+            return Ok(());
+        }
+        self.frames_requested += 1;
+        self.send_if_idle()
+    }
+
+    fn set_color(&mut self, key_color: KeyColor) -> Result<()> {
         let key_colors = vec![key_color];
         self.set_key_colors(key_colors)
     }
 
-    fn set_all_colors(&mut self, color: Color) -> UsbResult<()> {
-        let mut values = Key::values();
-        let key_colors = values.drain(..)
-            .filter(|k| match k {
-                // we can't set the color of media keys
-                &Key::Media(_) => false,
-                _ => true
-            }).map(|k| KeyColor::new(k, color.clone()))
+    /// Sets the color of all keys, using as few packets as possible.
+    fn set_all_colors(&mut self, color: Color) -> Result<()> {
+        let model = self.handle.model();
+        try!(self.set_zone_color(Zone::Keys, color.clone()));
+        if model.gaming_keys {
+            try!(self.set_zone_color(Zone::GamingKeys, color.clone()));
+        }
+        if model.logos.len() > 0 {
+            try!(self.set_zone_color(Zone::Logo, color));
+        }
+        Ok(())
+    }
+
+    /// Sets the color of all keys of given zone.
+    ///
+    /// Standard keys and logos are set with a single static native effect packet,
+    /// G-keys with a single color packet.
+    /// Returns `UnsupportedZone` if the model doesn't have any keys of the zone.
+    fn set_zone_color(&mut self, zone: Zone, color: Color) -> Result<()> {
+        let model = self.handle.model();
+        let keys: Vec<_> = model.keys().into_iter()
+            .filter(|k| Zone::of(k) == Some(zone))
             .collect();
-        self.set_key_colors(key_colors)
+        if keys.len() == 0 {
+            return Err(Error::UnsupportedZone(zone));
+        }
+        if zone.effect_id().is_none() {
+            let key_colors = keys.into_iter()
+                .map(|k| KeyColor::new(k, color.clone()))
+                .collect();
+            return self.set_key_colors(key_colors);
+        }
+        let resynced = try!(self.resync());
+        let unchanged = keys.iter().all(|k| self.colors.get(k) == Some(&color));
+        if !resynced && unchanged && !self.effects.contains_key(&zone) {
+            return Ok(());
+        }
+        self.effects.remove(&zone);
+        for key in keys {
+            self.pending_colors.remove(&key);
+            self.colors.insert(key, color.clone());
+        }
+        self.frames_requested += 1;
+        // colors of other zones still pending are sent behind this packet and mark the frame as sent
+        if self.pending_colors.len() == 0 {
+            self.frames_sent = self.frames_requested;
+        }
+        try!(self.send_effect(zone, NativeEffect::Color(color)));
+        self.send_if_idle()
+    }
+
+    /// Sets the brightness of all keys in percent, capped at 100.
+    ///
+    /// Colors are dimmed in software and resent, while `get_color` and `snapshot`
+    /// keep returning the undimmed colors.
+    /// `Color` and `Breathing` native effects are dimmed as well, but `ColorCycle` and `Wave`
+    /// keep their full brightness, as their colors are chosen by the firmware.
+    fn set_brightness(&mut self, percent: u8) -> Result<()> {
+        let percent = percent.min(100);
+        if percent == self.brightness {
+            return Ok(());
+        }
+        self.brightness = percent;
+        self.restore_colors()
     }
 
+    /// Starts a native effect, which is run by the keyboard itself and keeps running
+    /// without the host.
+    ///
+    /// The effect replaces all colors previously set for keys of the zone.
+    /// Returns `UnsupportedZone` for G-keys and if the model doesn't have logos.
+    fn set_native_effect(&mut self, zone: Zone, effect: NativeEffect) -> Result<()> {
+        let model = self.handle.model();
+        if zone.effect_id().is_none() || zone == Zone::Logo && model.logos.len() == 0 {
+            return Err(Error::UnsupportedZone(zone));
+        }
+        self.colors.retain(|k, _| Zone::of(k) != Some(zone));
+        self.pending_colors.retain(|k, _| Zone::of(k) != Some(zone));
+        self.effects.insert(zone, effect);
+        self.send_effect(zone, effect)
+    }
+
+    /// Returns the last color set for given key, or None if it hasn't been set yet.
+    fn get_color(&self, key: &Key) -> Option<Color> {
+        self.colors.get(key).cloned()
+    }
+
+    /// Returns the last color set for every key which has been set.
+    fn snapshot(&self) -> HashMap<Key, Color> {
+        self.colors.clone()
+    }
+
+    fn model(&self) -> &'static DeviceModel {
+        self.handle.model()
+    }
+
+    fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) {
+        self.reconnect_policy = policy;
+    }
+
+    /// Shorthand for changing the interval of a `Fixed` or `Unlimited` policy.
+    ///
+    /// Other policies are replaced by a `Fixed` one with 10 attempts.
     fn set_reconnect_interval(&mut self, interval: Duration) {
-        self.reconnect_interval = interval;
+        self.reconnect_policy = match self.reconnect_policy {
+            ReconnectPolicy::Fixed { attempts, .. } => ReconnectPolicy::Fixed {
+                interval: interval,
+                attempts: attempts,
+            },
+            ReconnectPolicy::Unlimited { .. } => ReconnectPolicy::Unlimited {
+                interval: interval,
+            },
+            ReconnectPolicy::ExponentialBackoff { .. } => ReconnectPolicy::Fixed {
+                interval: interval,
+                attempts: 10,
+            },
+        };
     }
 
+    /// Shorthand for changing the number of attempts of a `Fixed` policy.
+    ///
+    /// Negative values retry forever.
+    /// Other policies are replaced by a `Fixed` one with an interval of 1 second.
     fn set_reconnect_attempts(&mut self, attempts: i32) {
-        self.reconnect_attempts = attempts;
+        let interval = match self.reconnect_policy {
+            ReconnectPolicy::Fixed { interval, .. } => interval,
+            ReconnectPolicy::Unlimited { interval } => interval,
+            ReconnectPolicy::ExponentialBackoff { .. } => Duration::from_secs(1),
+        };
+        self.reconnect_policy = if attempts < 0 {
+            ReconnectPolicy::Unlimited {
+                interval: interval,
+            }
+        } else {
+            ReconnectPolicy::Fixed {
+                interval: interval,
+                attempts: attempts as u32,
+            }
+        };
     }
+
     fn set_auto_reconnect(&mut self, enabled: bool) {
         self.auto_reconnect = enabled;
     }
 
-    fn reconnect(&mut self) -> UsbResult<()> {
-        println!("Connection lost. Starting reconnect...");
-        let mut last_err = UsbError::NoDevice;
-        for _ in 0..self.reconnect_attempts {
-            match self.handle.reconnect() {
-                Ok(_) => {
-                    println!("Reconnected");
-                    return Ok(());
-                },
-                Err(e) => {
-                    println!("Reconnecting failed: {:?}", e);
-                    last_err = e;
-                }
-            }
-            ::std::thread::sleep(self.reconnect_interval);
-        }
-        Err(last_err)
+    /// Sets how long to wait for the acknowledgement of a control packet before resending it.
+    fn set_ack_timeout(&mut self, timeout: Duration) {
+        self.ack_timeout = timeout;
     }
 
+    /// Sets how often an unacknowledged control packet is resent before giving up.
+    fn set_ack_retries(&mut self, retries: u32) {
+        self.ack_retries = retries;
+    }
+
+    /// Reconnects to the keyboard according to the reconnect policy.
+    ///
+    /// If the transport supports hotplug, waits for the keyboard to be plugged in
+    /// again before each attempt.
+    /// Stops waiting and returns `Ok(())` if a stop is requested with a `StopToken` or a signal.
+    fn reconnect(&mut self) -> Result<()> {
+        self.reconnect_until(&mut |keyboard_internal| Ok(keyboard_internal.stop_requested())).map(|_| ())
+    }
+
+    /// Makes SIGINT and SIGTERM stop the handle loop, which then returns `Ok(())`.
+    ///
+    /// Dropping the keyboard afterwards releases its interfaces and reattaches the kernel driver.
     unsafe fn enable_signal_handling(&mut self) -> NixResult<()> {
-        let sig_action = SigAction::new(SigHandler::Handler(panic_on_sig), SaFlags::empty(), SigSet::empty());
+        if self.signal_pipe.is_none() {
+            let (read, write) = try!(unistd::pipe());
+            self.signal_pipe = Some((read, write));
+            try!(fcntl(read, FcntlArg::F_SETFL(O_NONBLOCK)));
+            // never block in the signal handler, a full pipe already means stopping
+            try!(fcntl(write, FcntlArg::F_SETFL(O_NONBLOCK)));
+        }
+        SIGNAL_PIPE.store(self.signal_pipe.unwrap().1 as isize, Ordering::SeqCst);
+        let sig_action = SigAction::new(SigHandler::Handler(write_signal_pipe), SaFlags::empty(), SigSet::empty());
         try!(sigaction(SIGINT, &sig_action).map(|_| ()));
         sigaction(SIGTERM, &sig_action).map(|_| ())
     }
 
+    /// Restores the default handlers of SIGINT and SIGTERM.
     fn disable_signal_handling(&mut self) -> NixResult<()> {
-        let sig_action = SigAction::new(SigHandler::Handler(exit_on_sig), SaFlags::empty(), SigSet::empty());
+        let sig_action = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
         unsafe {
             try!(sigaction(SIGINT, &sig_action).map(|_| ()));
-            sigaction(SIGTERM, &sig_action).map(|_| ())
+            try!(sigaction(SIGTERM, &sig_action).map(|_| ()));
         }
+        self.close_signal_pipe();
+        Ok(())
     }
 }
 
-extern fn panic_on_sig(_: i32) {
-    panic!("Got Sigint: Panicking to drop UsbWrapper to reattach kernel driver");
+impl Drop for KeyboardInternal {
+    fn drop(&mut self) {
+        if self.signal_pipe.is_some() {
+            let _ = self.disable_signal_handling();
+        }
+    }
 }
 
-extern fn exit_on_sig(_: i32) {
-    ::std::process::exit(1);
+// write end of the signal pipe of the keyboard handling signals, -1 if none
+static SIGNAL_PIPE: AtomicIsize = AtomicIsize::new(-1);
+
+extern fn write_signal_pipe(_: i32) {
+    // only async-signal-safe functions may be called here
+    let fd = SIGNAL_PIPE.load(Ordering::SeqCst);
+    if fd >= 0 {
+        unsafe { libc::write(fd as libc::c_int, b"s".as_ptr() as *const libc::c_void, 1) };
+    }
 }
 
+// how long the handle loop waits for USB events before checking for
+// remote commands, stop requests and signals
+const COMMAND_POLL_INTERVAL_MS: u64 = 50;
+
 pub struct KeyboardImpl {
     keyboard_internal: KeyboardInternal,
     parser_index: u32,
     parsers: HashMap<u32, Parser>,
-    handler_index: u32,
+    handler_index: Arc<AtomicUsize>,
     handlers: HashMap<u32, Box<GenericHandler>>,
+    passthrough: Option<Box<KeySink>>,
+    remote: Option<Remote>,
+    commands: Option<Receiver<Command>>,
 }
 
 impl KeyboardImpl {
-    pub fn new() -> UsbResult<KeyboardImpl> {
+    pub fn new() -> Result<KeyboardImpl> {
+        Ok(KeyboardImpl::from_internal(try!(KeyboardInternal::new()), true))
+    }
+
+    /// Opens the keyboard matching given selector.
+    ///
+    /// On reconnect the same physical keyboard will be searched for.
+    /// Use `g910::devices()` to list all connected keyboards.
+    pub fn open(selector: DeviceSelector) -> Result<KeyboardImpl> {
+        KeyboardImpl::open_with_mode(selector, ConnectionMode::Full)
+    }
+
+    /// Opens the keyboard matching given selector in given mode.
+    ///
+    /// In `LightingOnly` mode keys aren't parsed, so no key events are generated.
+    pub fn open_with_mode(selector: DeviceSelector, mode: ConnectionMode) -> Result<KeyboardImpl> {
+        let keyboard_internal = try!(KeyboardInternal::open(selector, mode));
+        Ok(KeyboardImpl::from_internal(keyboard_internal, mode == ConnectionMode::Full))
+    }
+
+    /// Opens the first keyboard through hidraw instead of libusb.
+    ///
+    /// The kernel driver stays attached, so the keyboard keeps typing.
+    #[cfg(target_os = "linux")]
+    pub fn open_hidraw() -> Result<KeyboardImpl> {
+        let transport = try!(HidrawTransport::new().map_err(not_found));
+        Ok(KeyboardImpl::with_transport(Box::new(transport)))
+    }
+
+    /// Creates a keyboard communicating over given transport instead of libusb.
+    pub fn with_transport(transport: Box<Transport>) -> KeyboardImpl {
+        KeyboardImpl::from_internal(KeyboardInternal::with_transport(transport), true)
+    }
+
+    fn from_internal(keyboard_internal: KeyboardInternal, parse_keys: bool) -> KeyboardImpl {
         let mut keyboard = KeyboardImpl {
-            keyboard_internal: try!(KeyboardInternal::new()),
+            keyboard_internal: keyboard_internal,
             parser_index: 0,
             parsers: HashMap::new(),
-            handler_index: 0,
+            handler_index: Arc::new(AtomicUsize::new(0)),
             handlers: HashMap::new(),
+            passthrough: None,
+            remote: None,
+            commands: None,
         };
-        keyboard.add_parser(KeyParser::new().into());
+        if parse_keys {
+            keyboard.add_parser(KeyParser::new().into());
+        }
         keyboard.add_parser(ControlParser::new().into());
-        Ok(keyboard)
+        keyboard
     }
 
     pub fn add_handler(&mut self, handler: Handler) -> u32 {
-        let index = self.handler_index;
+        let index = self.handler_index.fetch_add(1, Ordering::SeqCst) as u32;
         self.handlers.insert(index, handler.into());
-        self.handler_index += 1;
         index
     }
 
@@ -216,6 +874,93 @@ impl KeyboardImpl {
         self.handlers.remove(&index)
     }
 
+    /// Sets the sink receiving all key events which weren't swallowed by a handler.
+    pub fn set_passthrough(&mut self, sink: Option<Box<KeySink>>) {
+        self.passthrough = sink;
+    }
+
+    /// Passes key events through to the OS via a virtual uinput keyboard,
+    /// so the keyboard keeps typing while it is claimed.
+    ///
+    /// Handlers can prevent events from being passed through with `HandlerBuilder::swallow_key_fn`.
+    #[cfg(target_os = "linux")]
+    pub fn enable_uinput_passthrough(&mut self) -> Result<()> {
+        let sink = try!(UinputSink::new());
+        self.set_passthrough(Some(Box::new(sink)));
+        Ok(())
+    }
+
+    /// Returns a handle which can be used to control the keyboard from other threads
+    /// while the handle loop is running.
+    ///
+    /// Once a remote has been created, the handle loop checks for commands every 50ms.
+    pub fn remote(&mut self) -> Remote {
+        match self.remote {
+            Some(ref remote) => return remote.clone(),
+            None => {}
+        }
+        let (sender, receiver) = mpsc::channel();
+        let remote = Remote::new(sender, self.handler_index.clone());
+        self.commands = Some(receiver);
+        self.remote = Some(remote.clone());
+        remote
+    }
+
+    /// Returns a token which makes the handle loop return `Ok(())` when stopped.
+    ///
+    /// Once a token has been created, the handle loop checks it every 50ms.
+    pub fn stop_token(&mut self) -> StopToken {
+        match self.keyboard_internal.stop_token {
+            Some(ref token) => return token.clone(),
+            None => {}
+        }
+        let token = StopToken::new();
+        self.keyboard_internal.stop_token = Some(token.clone());
+        token
+    }
+
+    /// Whether the handle loop must wake up regularly to check for remote commands, stop requests and signals.
+    fn must_poll(&self) -> bool {
+        self.commands.is_some() || self.keyboard_internal.stop_token.is_some()
+            || self.keyboard_internal.signal_pipe.is_some()
+    }
+
+    /// Executes all commands sent by remotes.
+    ///
+    /// Returns false if shutdown was requested.
+    fn process_commands(&mut self) -> Result<bool> {
+        let &mut KeyboardImpl {
+            ref mut keyboard_internal,
+            ref mut handlers,
+            ref commands,
+            ..
+        } = self;
+        process_commands(commands, handlers, keyboard_internal)
+    }
+
+    /// Reconnects like `reconnect`, but keeps executing remote commands while waiting.
+    ///
+    /// Returns false if shutdown or stop was requested before the keyboard was reconnected.
+    fn reconnect_or_stop(&mut self) -> Result<bool> {
+        let &mut KeyboardImpl {
+            ref mut keyboard_internal,
+            ref mut handlers,
+            ref commands,
+            ..
+        } = self;
+        keyboard_internal.reconnect_until(&mut |keyboard_internal| {
+            match process_commands(commands, handlers, keyboard_internal) {
+                Ok(running) => Ok(!running || keyboard_internal.stop_requested()),
+                // the colors are sent once reconnected
+                Err(Error::Usb(e)) => {
+                    debug!(target: consts::LOG_RECONNECT, "Remote command not sent while disconnected: {}", e);
+                    Ok(keyboard_internal.stop_requested())
+                },
+                Err(e) => Err(e),
+            }
+        })
+    }
+
     fn add_parser(&mut self, parser: Parser) -> u32 {
         let index = self.parser_index;
         self.parsers.insert(index, parser);
@@ -228,41 +973,84 @@ impl KeyboardImpl {
         //self.parsers.remove(&index)
     //}
 
-    fn handle(&mut self) -> UsbResult<()> {
+    /// Returns false if shutdown was requested.
+    fn handle(&mut self) -> Result<bool> {
+        let (endpoint_direction, buf) = match try!(self.recv()) {
+            Some(transfer) => transfer,
+            None => return Ok(false),
+        };
+        try!(self.dispatch(endpoint_direction, &buf));
+        Ok(true)
+    }
+
+    /// Waits for the next transfer, calling timed handlers and executing remote commands while waiting.
+    ///
+    /// Returns None if shutdown or stop was requested.
+    fn recv(&mut self) -> Result<Option<(u8, Vec<u8>)>> {
+        loop {
+            if !try!(self.process_commands()) || self.keyboard_internal.stop_requested() {
+                return Ok(None);
+            }
+            try!(self.check_ack_timeout());
+            let mut timeout = match self.next_timeout() {
+                Some(d) => d,
+                None => Duration::from_secs(3600*24*365)
+            };
+            if self.must_poll() && timeout > Duration::from_millis(COMMAND_POLL_INTERVAL_MS) {
+                timeout = Duration::from_millis(COMMAND_POLL_INTERVAL_MS);
+            }
+            match self.keyboard_internal.handle.recv(timeout) {
+                Some(Ok(transfer)) => return Ok(Some(transfer)),
+                Some(Err(err)) => return Err(err.into()),
+                None => try!(self.call_timed_handlers()),
+            }
+        }
+    }
+
+    /// Resends the control packet waiting for its acknowledgement if its deadline has passed.
+    ///
+    /// Dropped frames have already been logged and are reported through `frame_acknowledged`,
+    /// so `AckTimeout` doesn't stop handling events.
+    fn check_ack_timeout(&mut self) -> Result<()> {
+        match self.keyboard_internal.check_ack_timeout() {
+            Err(Error::AckTimeout) => Ok(()),
+            res => res,
+        }
+    }
+
+    /// Calls all timed handlers which are due.
+    fn call_timed_handlers(&mut self) -> Result<()> {
+        let &mut KeyboardImpl {
+            ref mut keyboard_internal,
+            ref mut handlers,
+            ..
+        } = self;
+        for handler in handlers.iter_mut().filter_map(|(_,h)| match h.sleep_duration() {
+            Some(dur) if dur == Duration::from_secs(0) => Some(h),
+            _ => None
+        }) {
+            try!(handler.handle_time(keyboard_internal));
+        }
+        Ok(())
+    }
+
+    /// Parses a transfer received from given endpoint and dispatches it to the handlers,
+    /// just like the handle loop does.
+    ///
+    /// Returns the parsed key events.
+    pub fn dispatch(&mut self, endpoint_direction: u8, buf: &[u8]) -> Result<Vec<KeyEvent>> {
         let &mut KeyboardImpl {
             ref mut keyboard_internal,
             parser_index: _,
             ref mut parsers,
             handler_index: _,
             ref mut handlers,
+            ref mut passthrough,
+            ..
         } = self;
 
-        let endpoint_direction;
-        let buf;
-        loop {
-            let timeout = match handlers.iter().filter_map(|(_,h)| h.sleep_duration()).min() {
-                Some(d) => d,
-                None => Duration::from_secs(3600*24*365)
-            };
-            let res = keyboard_internal.handle.recv(timeout);
-            match res {
-                Some(Ok((e, b))) => {
-                    endpoint_direction = e;
-                    buf = b;
-                    break
-                },
-                Some(Err(err)) => return Err(err),
-                None => {
-                    for handler in handlers.iter_mut().filter_map(|(_,h)| match h.sleep_duration() {
-                        Some(dur) if dur == Duration::from_secs(0) => Some(h),
-                        _ => None
-                    }) {
-                        try!(handler.handle_time(keyboard_internal));
-                    }
-                }
-            }
-        }
-        let packet = Packet::new(endpoint_direction, &buf);
+        let packet = Packet::new(endpoint_direction, buf);
+        let mut events = Vec::new();
         let mut handled = false;
         let mut parsed = false;
         for (_, parser) in parsers.iter_mut() {
@@ -271,12 +1059,22 @@ impl KeyboardImpl {
                     parsed = true;
                     let key_events = try!(p.parse(&packet, keyboard_internal));
                     for key_event in key_events {
+                        let mut swallowed = false;
                         for (_, handler) in handlers.iter_mut() {
                             if handler.accept_key(&key_event) {
                                 handled = true;
+                                swallowed |= handler.swallow_key(&key_event);
                                 try!(handler.handle_key(&key_event, keyboard_internal));
                             }
                         }
+                        match passthrough {
+                            &mut Some(ref mut sink) if !swallowed => {
+                                handled = true;
+                                try!(sink.emit(&key_event));
+                            },
+                            _ => {}
+                        }
+                        events.push(key_event);
                     }
                 },
                 &mut Parser::ParseControl(ref mut p) if p.accept(&packet) => {
@@ -288,47 +1086,167 @@ impl KeyboardImpl {
             }
         }
         if !parsed {
-            println!("Packet not parsed: {:?}", packet);
+            debug!(target: consts::LOG_PARSER, "Packet not parsed on endpoint {}: {}", packet.endpoint, Hex(packet.buf));
         } else if !handled {
-            println!("Packet not handled: {:?}", packet);
+            debug!(target: consts::LOG_PARSER, "Packet not handled on endpoint {}: {}", packet.endpoint, Hex(packet.buf));
         }
-        Ok(())
+        Ok(events)
     }
 
-    pub fn start_handle_loop(&mut self) -> UsbResult<()> {
-        // init handlers
-        {
-            let &mut KeyboardImpl {
-                ref mut keyboard_internal,
-                parser_index: _,
-                parsers: _,
-                handler_index: _,
-                ref mut handlers,
-            } = self;
-            for (_, handler) in handlers {
-                try!(handler.init(keyboard_internal));
-            }
+    /// Handles at most one completed transfer without blocking and calls due timed handlers.
+    ///
+    /// Returns the parsed key events, or None if no transfer had completed.
+    pub fn poll_events(&mut self) -> Result<Option<Vec<KeyEvent>>> {
+        try!(self.check_ack_timeout());
+        match self.keyboard_internal.handle.recv(Duration::from_secs(0)) {
+            Some(Ok((endpoint_direction, buf))) => return self.dispatch(endpoint_direction, &buf).map(Some),
+            Some(Err(err)) => return Err(err.into()),
+            None => {}
+        }
+        try!(self.call_timed_handlers());
+        Ok(None)
+    }
+
+    /// Returns the time until the next timed handler or ack deadline is due.
+    pub fn next_timeout(&self) -> Option<Duration> {
+        self.handlers.iter().filter_map(|(_,h)| h.sleep_duration())
+            .chain(self.keyboard_internal.ack_time_left()).min()
+    }
+
+    /// Returns the file descriptors of the transport, see `Transport::pollfds`.
+    pub fn pollfds(&self) -> Vec<(RawFd, i16)> {
+        self.keyboard_internal.handle.pollfds()
+    }
+
+    /// Returns the number of the last frame, i.e. the last `set_key_colors` call which changed colors.
+    pub fn last_frame(&self) -> u64 {
+        self.keyboard_internal.last_frame()
+    }
+
+    /// Returns whether all packets of given frame have been acknowledged by the keyboard.
+    pub fn frame_acknowledged(&self, frame: u64) -> Option<Result<()>> {
+        self.keyboard_internal.frame_acknowledged(frame)
+    }
+
+    /// Reports a lost connection if given error means the keyboard is gone.
+    ///
+    /// Returns whether the caller should reconnect with `begin_reconnect` and `reconnect_step`,
+    /// i.e. the connection was lost and auto reconnect is enabled.
+    pub fn connection_lost(&mut self, err: &Error) -> bool {
+        if !is_disconnect(err) {
+            return false;
         }
+        self.keyboard_internal.disconnected(err);
+        self.keyboard_internal.auto_reconnect
+    }
+
+    /// Starts reconnecting without blocking, see `reconnect_step`.
+    pub fn begin_reconnect(&mut self) {
+        self.keyboard_internal.begin_reconnect()
+    }
+
+    /// Makes the next reconnect attempt without blocking.
+    ///
+    /// Returns None once reconnected, otherwise the time to wait before calling it again.
+    /// Fails once the reconnect policy has no attempts left.
+    pub fn reconnect_step(&mut self) -> Result<Option<Duration>> {
+        self.keyboard_internal.reconnect_step()
+    }
+
+    /// Calls `init` of all handlers.
+    pub fn init_handlers(&mut self) -> Result<()> {
+        let &mut KeyboardImpl {
+            ref mut keyboard_internal,
+            parser_index: _,
+            parsers: _,
+            handler_index: _,
+            ref mut handlers,
+            ..
+        } = self;
+        for (_, handler) in handlers {
+            try!(handler.init(keyboard_internal));
+        }
+        Ok(())
+    }
+
+    /// Handles all events until an error occurs.
+    ///
+    /// Returns `Ok(())` once shutdown is requested through a `Remote`, the `StopToken`
+    /// is stopped or a signal is received while signal handling is enabled.
+    /// Dropping the keyboard afterwards releases its interfaces and reattaches the kernel driver.
+    pub fn start_handle_loop(&mut self) -> Result<()> {
+        try!(self.init_handlers());
         loop {
             match self.handle() {
-                Ok(()) => {},
-                Err(UsbError::NoDevice) | Err(UsbError::Io) | Err(UsbError::Busy) => try!(self.reconnect()),
-                Err(e) => return Err(e),
+                Ok(true) => {},
+                Ok(false) => {
+                    self.keyboard_internal.reset_stop();
+                    return Ok(());
+                },
+                // a handler's colors were dropped, the next frame resyncs them
+                Err(Error::AckTimeout) => {
+                    warn!(target: consts::LOG_TRANSPORT, "Handler failed: {}", Error::AckTimeout);
+                },
+                Err(e) => {
+                    if !self.connection_lost(&e) {
+                        return Err(e);
+                    }
+                    if !try!(self.reconnect_or_stop()) {
+                        self.keyboard_internal.reset_stop();
+                        return Ok(());
+                    }
+                },
             }
         }
     }
+
+    /// Called with the error when the handle loop loses the connection to the keyboard.
+    pub fn on_disconnected<F: 'static + FnMut(&Error)>(&mut self, f: F) {
+        self.keyboard_internal.on_disconnected(f)
+    }
+
+    /// Called with the attempt number (starting at 1) before each reconnect attempt.
+    pub fn on_reconnecting<F: 'static + FnMut(u32)>(&mut self, f: F) {
+        self.keyboard_internal.on_reconnecting(f)
+    }
+
+    /// Called after the keyboard has been reconnected successfully.
+    pub fn on_reconnected<F: 'static + FnMut()>(&mut self, f: F) {
+        self.keyboard_internal.on_reconnected(f)
+    }
 }
 
 impl Keyboard for KeyboardImpl {
-    fn set_key_colors(&mut self, key_colors: Vec<KeyColor>) -> UsbResult<()> {
+    fn set_key_colors(&mut self, key_colors: Vec<KeyColor>) -> Result<()> {
         self.keyboard_internal.set_key_colors(key_colors)
     }
-    fn set_color(&mut self, key_color: KeyColor) -> UsbResult<()> {
+    fn set_color(&mut self, key_color: KeyColor) -> Result<()> {
         self.keyboard_internal.set_color(key_color)
     }
-    fn set_all_colors(&mut self, color: Color) -> UsbResult<()> {
+    fn set_all_colors(&mut self, color: Color) -> Result<()> {
         self.keyboard_internal.set_all_colors(color)
     }
+    fn set_zone_color(&mut self, zone: Zone, color: Color) -> Result<()> {
+        self.keyboard_internal.set_zone_color(zone, color)
+    }
+    fn set_brightness(&mut self, percent: u8) -> Result<()> {
+        self.keyboard_internal.set_brightness(percent)
+    }
+    fn set_native_effect(&mut self, zone: Zone, effect: NativeEffect) -> Result<()> {
+        self.keyboard_internal.set_native_effect(zone, effect)
+    }
+    fn get_color(&self, key: &Key) -> Option<Color> {
+        self.keyboard_internal.get_color(key)
+    }
+    fn snapshot(&self) -> HashMap<Key, Color> {
+        self.keyboard_internal.snapshot()
+    }
+    fn model(&self) -> &'static DeviceModel {
+        self.keyboard_internal.model()
+    }
+    fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) {
+        self.keyboard_internal.set_reconnect_policy(policy)
+    }
     fn set_reconnect_interval(&mut self, interval: Duration) {
         self.keyboard_internal.set_reconnect_interval(interval)
     }
@@ -338,8 +1256,14 @@ impl Keyboard for KeyboardImpl {
     fn set_auto_reconnect(&mut self, enabled: bool) {
         self.keyboard_internal.set_auto_reconnect(enabled)
     }
-    fn reconnect(&mut self) -> UsbResult<()> {
-        self.keyboard_internal.reconnect()
+    fn set_ack_timeout(&mut self, timeout: Duration) {
+        self.keyboard_internal.set_ack_timeout(timeout)
+    }
+    fn set_ack_retries(&mut self, retries: u32) {
+        self.keyboard_internal.set_ack_retries(retries)
+    }
+    fn reconnect(&mut self) -> Result<()> {
+        self.reconnect_or_stop().map(|_| ())
     }
     unsafe fn enable_signal_handling(&mut self) -> NixResult<()> {
         self.keyboard_internal.enable_signal_handling()
@@ -349,3 +1273,602 @@ impl Keyboard for KeyboardImpl {
     }
 }
 
+/// Executes all commands sent by remotes.
+///
+/// A command failing doesn't prevent the following ones from being executed,
+/// the first error which must stop the handle loop is returned once all have been executed.
+/// Returns false if shutdown was requested.
+fn process_commands(commands: &Option<Receiver<Command>>, handlers: &mut HashMap<u32, Box<GenericHandler>>,
+                    keyboard_internal: &mut KeyboardInternal) -> Result<bool> {
+    let commands: Vec<_> = match *commands {
+        Some(ref receiver) => receiver.try_iter().collect(),
+        None => return Ok(true),
+    };
+    let mut result = Ok(true);
+    for command in commands {
+        let res = match command {
+            Command::SetKeyColors(key_colors) => keyboard_internal.set_key_colors(key_colors),
+            Command::SetAllColors(color) => keyboard_internal.set_all_colors(color),
+            Command::SetZoneColor(zone, color) => keyboard_internal.set_zone_color(zone, color),
+            Command::SetBrightness(percent) => keyboard_internal.set_brightness(percent),
+            Command::SetNativeEffect(zone, effect) => keyboard_internal.set_native_effect(zone, effect),
+            Command::AddHandler(index, mut f) => {
+                let mut handler: Box<GenericHandler> = f().into();
+                let res = handler.init(keyboard_internal);
+                handlers.insert(index, handler);
+                res
+            },
+            Command::RemoveHandler(index) => {
+                handlers.remove(&index);
+                Ok(())
+            },
+            Command::Shutdown => return Ok(false),
+        };
+        match res {
+            Ok(()) => {},
+            // a bad command shouldn't stop the handle loop
+            Err(e @ Error::UnsupportedKey(_)) | Err(e @ Error::UnsupportedZone(_)) => {
+                warn!(target: consts::LOG_REMOTE, "Remote command failed: {}", e);
+            },
+            Err(e) => if result.is_ok() {
+                result = Err(e);
+            },
+        }
+    }
+    result
+}
+
+/// Reports a keyboard which wasn't found while opening as `DeviceNotFound`.
+fn not_found(err: UsbError) -> Error {
+    match err {
+        UsbError::NoDevice | UsbError::NotFound => Error::DeviceNotFound {
+            vendor_id: consts::VENDOR_ID,
+            product_ids: MODELS.iter().flat_map(|m| m.product_ids.iter().cloned()).collect(),
+        },
+        err => Error::Usb(err),
+    }
+}
+
+/// Returns whether given error means the connection to the keyboard was lost.
+fn is_disconnect(err: &Error) -> bool {
+    match *err {
+        Error::Usb(UsbError::NoDevice) | Error::Usb(UsbError::Io) | Error::Usb(UsbError::Busy) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Duration;
+    use libusb::Error as UsbError;
+    use color::{Color, ColorPacket, FlushPacket, KeyColor};
+    use event::KeyEvent;
+    use error::Error;
+    use handle::ToControlPacket;
+    use keys::{GamingKey, Key, StandardKey};
+    use model;
+    use fake::FakeDevice;
+    use native_effect::{NativeEffect, NativeEffectPacket, Zone};
+    use reconnect::ReconnectPolicy;
+    use super::{Keyboard, KeyboardImpl};
+
+    fn keyboard(device: &FakeDevice) -> KeyboardImpl {
+        KeyboardImpl::with_transport(Box::new(device.clone()))
+    }
+
+    // handles all queued transfers
+    fn drain(keyboard: &mut KeyboardImpl) -> Vec<KeyEvent> {
+        let mut events = Vec::new();
+        while let Some(e) = keyboard.poll_events().unwrap() {
+            events.extend(e);
+        }
+        events
+    }
+
+    #[test]
+    fn late_ack_of_resent_packet_is_ignored() {
+        let device = FakeDevice::new();
+        device.set_auto_ack(false);
+        let mut keyboard = keyboard(&device);
+        keyboard.set_color(KeyColor::new(StandardKey::A, Color::new(255, 0, 0))).unwrap();
+        let sent = device.take_sent_control_packets();
+        assert_eq!(sent.len(), 1);
+        // acks of the original packet and of its resend
+        device.ack(&sent[0]);
+        device.ack(&sent[0]);
+        drain(&mut keyboard);
+        let flush = device.take_sent_control_packets();
+        assert_eq!(flush.len(), 1);
+        let frame = keyboard.last_frame();
+        assert!(keyboard.frame_acknowledged(frame).is_none());
+        device.ack(&flush[0]);
+        drain(&mut keyboard);
+        assert!(keyboard.frame_acknowledged(frame).unwrap().is_ok());
+    }
+
+    #[test]
+    fn ack_timeout_drops_frame_without_failing() {
+        let device = FakeDevice::new();
+        device.set_auto_ack(false);
+        let mut keyboard = keyboard(&device);
+        keyboard.set_ack_timeout(Duration::from_millis(1));
+        keyboard.set_ack_retries(0);
+        keyboard.set_color(KeyColor::new(StandardKey::A, Color::new(255, 0, 0))).unwrap();
+        let frame = keyboard.last_frame();
+        ::std::thread::sleep(Duration::from_millis(5));
+        drain(&mut keyboard);
+        assert!(keyboard.frame_acknowledged(frame).unwrap().is_err());
+    }
+
+    #[test]
+    fn shutdown_while_reconnecting_stops_handle_loop() {
+        let device = FakeDevice::new();
+        let mut keyboard = keyboard(&device);
+        keyboard.set_reconnect_policy(ReconnectPolicy::Unlimited {
+            interval: Duration::from_secs(3600),
+        });
+        let remote = keyboard.remote();
+        keyboard.on_reconnecting(move |_| remote.shutdown().unwrap());
+        device.set_connected(false);
+        assert!(keyboard.start_handle_loop().is_ok());
+        assert_eq!(device.reconnects(), 0);
+    }
+
+    #[test]
+    fn stop_while_reconnecting_stops_handle_loop() {
+        let device = FakeDevice::new();
+        let mut keyboard = keyboard(&device);
+        keyboard.set_reconnect_policy(ReconnectPolicy::Unlimited {
+            interval: Duration::from_secs(3600),
+        });
+        let token = keyboard.stop_token();
+        let stopper = token.clone();
+        keyboard.on_reconnecting(move |_| stopper.stop());
+        device.set_connected(false);
+        assert!(keyboard.start_handle_loop().is_ok());
+        // the token can be reused for the next run
+        assert!(!token.is_stopped());
+    }
+
+    #[test]
+    fn none_keys_are_unsupported() {
+        let device = FakeDevice::new();
+        let mut keyboard = keyboard(&device);
+        let black = Color::new(0, 0, 0);
+        match keyboard.set_color(KeyColor::new(StandardKey::None, black)) {
+            Err(Error::UnsupportedKey(_)) => {},
+            res => panic!("unexpected result {:?}", res),
+        }
+        match keyboard.set_color(KeyColor::new(GamingKey::None, black)) {
+            Err(Error::UnsupportedKey(_)) => {},
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(device.sent_control_packets().is_empty());
+    }
+
+    #[test]
+    fn colors_are_sent_with_flush() {
+        let device = FakeDevice::new();
+        let mut keyboard = keyboard(&device);
+        keyboard.set_color(KeyColor::new(StandardKey::A, Color::new(255, 0, 0))).unwrap();
+        drain(&mut keyboard);
+        let mut color = ColorPacket::new(&model::G910);
+        color.add(StandardKey::A, Color::new(255, 0, 0));
+        assert_eq!(device.sent_control_packets(), vec![
+            color.to_control_packet(),
+            FlushPacket::new(&model::G910).to_control_packet(),
+        ]);
+        let frame = keyboard.last_frame();
+        assert!(keyboard.frame_acknowledged(frame).unwrap().is_ok());
+    }
+
+    #[test]
+    fn control_packets_wait_for_ack() {
+        let device = FakeDevice::new();
+        device.set_auto_ack(false);
+        let mut keyboard = keyboard(&device);
+        // A to T, more than fit into one color packet
+        let key_colors = (0x04..0x18)
+            .map(|k| KeyColor::new(StandardKey::from(k), Color::new(0, 0, 255)))
+            .collect();
+        keyboard.set_key_colors(key_colors).unwrap();
+        let frame = keyboard.last_frame();
+        // colors, colors, flush
+        for _ in 0..3 {
+            drain(&mut keyboard);
+            let sent = device.take_sent_control_packets();
+            assert_eq!(sent.len(), 1);
+            assert!(keyboard.frame_acknowledged(frame).is_none());
+            device.ack(&sent[0]);
+        }
+        drain(&mut keyboard);
+        assert!(device.take_sent_control_packets().is_empty());
+        assert!(keyboard.frame_acknowledged(frame).unwrap().is_ok());
+    }
+
+    #[test]
+    fn key_reports_are_parsed() {
+        let device = FakeDevice::new();
+        let mut keyboard = keyboard(&device);
+        device.emit_interrupt(0x81, vec![0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(drain(&mut keyboard), vec![KeyEvent::KeyPressed(Key::Standard(StandardKey::A))]);
+        device.emit_interrupt(0x81, vec![0x00; 8]);
+        assert_eq!(drain(&mut keyboard), vec![KeyEvent::KeyReleased(Key::Standard(StandardKey::A))]);
+    }
+
+    #[test]
+    fn remote_command_between_failed_reconnect_attempts() {
+        let device = FakeDevice::new();
+        let mut keyboard = keyboard(&device);
+        keyboard.set_reconnect_policy(ReconnectPolicy::Fixed {
+            interval: Duration::from_millis(1),
+            attempts: 5,
+        });
+        let remote = keyboard.remote();
+        let replug = device.clone();
+        let red = Color::new(255, 0, 0);
+        keyboard.on_reconnecting(move |attempt| match attempt {
+            // sent while the device is gone, as the first attempt fails
+            1 => remote.set_color(KeyColor::new(StandardKey::A, red)).unwrap(),
+            _ => replug.set_connected(true),
+        });
+        let token = keyboard.stop_token();
+        keyboard.on_reconnected(move || token.stop());
+        device.set_connected(false);
+        assert!(keyboard.start_handle_loop().is_ok());
+        assert_eq!(device.reconnects(), 1);
+        assert_eq!(keyboard.get_color(&Key::Standard(StandardKey::A)), Some(red));
+        let mut color = ColorPacket::new(&model::G910);
+        color.add(StandardKey::A, red);
+        assert!(device.sent_control_packets().contains(&color.to_control_packet()));
+    }
+
+    #[test]
+    fn late_ack_is_not_taken_for_next_packet() {
+        let device = FakeDevice::new();
+        device.set_auto_ack(false);
+        let mut keyboard = keyboard(&device);
+        keyboard.set_ack_timeout(Duration::from_millis(1));
+        // A to T, two color packets with the same header
+        let key_colors = (0x04..0x18)
+            .map(|k| KeyColor::new(StandardKey::from(k), Color::new(0, 0, 255)))
+            .collect();
+        keyboard.set_key_colors(key_colors).unwrap();
+        let frame = keyboard.last_frame();
+        ::std::thread::sleep(Duration::from_millis(5));
+        keyboard.set_ack_timeout(Duration::from_secs(3600));
+        drain(&mut keyboard);
+        let sent = device.take_sent_control_packets();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0], sent[1]);
+        // acks of the original packet and of its resend
+        device.ack(&sent[0]);
+        device.ack(&sent[0]);
+        drain(&mut keyboard);
+        let second = device.take_sent_control_packets();
+        assert_eq!(second.len(), 1);
+        assert_eq!(second[0].buf()[..4], sent[0].buf()[..4]);
+        assert!(keyboard.frame_acknowledged(frame).is_none());
+        device.ack(&second[0]);
+        drain(&mut keyboard);
+        let flush = device.take_sent_control_packets();
+        assert_eq!(flush, vec![FlushPacket::new(&model::G910).to_control_packet()]);
+        device.ack(&flush[0]);
+        drain(&mut keyboard);
+        assert!(keyboard.frame_acknowledged(frame).unwrap().is_ok());
+    }
+
+    #[test]
+    fn dropped_frames_stay_dropped() {
+        let device = FakeDevice::new();
+        device.set_auto_ack(false);
+        let mut keyboard = keyboard(&device);
+        keyboard.set_ack_timeout(Duration::from_millis(1));
+        keyboard.set_ack_retries(0);
+        keyboard.set_color(KeyColor::new(StandardKey::A, Color::new(255, 0, 0))).unwrap();
+        let first = keyboard.last_frame();
+        ::std::thread::sleep(Duration::from_millis(5));
+        drain(&mut keyboard);
+        device.set_auto_ack(true);
+        keyboard.set_ack_timeout(Duration::from_secs(3600));
+        keyboard.set_color(KeyColor::new(StandardKey::B, Color::new(255, 0, 0))).unwrap();
+        let second = keyboard.last_frame();
+        drain(&mut keyboard);
+        device.set_auto_ack(false);
+        keyboard.set_ack_timeout(Duration::from_millis(1));
+        keyboard.set_color(KeyColor::new(StandardKey::C, Color::new(255, 0, 0))).unwrap();
+        let third = keyboard.last_frame();
+        ::std::thread::sleep(Duration::from_millis(5));
+        drain(&mut keyboard);
+        assert!(keyboard.frame_acknowledged(first).unwrap().is_err());
+        assert!(keyboard.frame_acknowledged(second).unwrap().is_ok());
+        assert!(keyboard.frame_acknowledged(third).unwrap().is_err());
+    }
+
+    #[test]
+    fn colors_set_while_busy_are_merged_into_one_frame() {
+        let device = FakeDevice::new();
+        device.set_auto_ack(false);
+        let mut keyboard = keyboard(&device);
+        let red = Color::new(255, 0, 0);
+        let green = Color::new(0, 255, 0);
+        keyboard.set_color(KeyColor::new(StandardKey::A, red)).unwrap();
+        let first = keyboard.last_frame();
+        keyboard.set_color(KeyColor::new(StandardKey::C, red)).unwrap();
+        keyboard.set_color(KeyColor::new(StandardKey::B, red)).unwrap();
+        keyboard.set_color(KeyColor::new(StandardKey::B, green)).unwrap();
+        let last = keyboard.last_frame();
+        assert_eq!(last, first + 3);
+        assert_eq!(keyboard.keyboard_internal.frames_sent, first);
+        // colors and flush of the first frame
+        for _ in 0..2 {
+            drain(&mut keyboard);
+            let sent = device.take_sent_control_packets();
+            assert_eq!(sent.len(), 1);
+            device.ack(&sent[0]);
+        }
+        drain(&mut keyboard);
+        assert!(keyboard.frame_acknowledged(first).unwrap().is_ok());
+        assert!(keyboard.frame_acknowledged(first + 1).is_none());
+        assert_eq!(keyboard.keyboard_internal.frames_sent, last);
+        // the merged frame with the latest color of each key, in key order
+        let mut color = ColorPacket::new(&model::G910);
+        color.add(StandardKey::B, green);
+        color.add(StandardKey::C, red);
+        let sent = device.take_sent_control_packets();
+        assert_eq!(sent, vec![color.to_control_packet()]);
+        device.ack(&sent[0]);
+        drain(&mut keyboard);
+        let sent = device.take_sent_control_packets();
+        assert_eq!(sent, vec![FlushPacket::new(&model::G910).to_control_packet()]);
+        device.ack(&sent[0]);
+        drain(&mut keyboard);
+        assert!(device.take_sent_control_packets().is_empty());
+        assert_eq!(keyboard.keyboard_internal.frames_acknowledged, last);
+        for frame in first..last + 1 {
+            assert!(keyboard.frame_acknowledged(frame).unwrap().is_ok());
+        }
+    }
+
+    #[test]
+    fn colors_are_packed_in_key_order() {
+        let device = FakeDevice::new();
+        let mut keyboard = keyboard(&device);
+        let blue = Color::new(0, 0, 255);
+        // T to A, more than fit into one color packet
+        let key_colors = (0x04..0x18).rev()
+            .map(|k| KeyColor::new(StandardKey::from(k), blue))
+            .collect();
+        keyboard.set_key_colors(key_colors).unwrap();
+        drain(&mut keyboard);
+        let mut first = ColorPacket::new(&model::G910);
+        let mut second = ColorPacket::new(&model::G910);
+        for k in 0x04..0x12 {
+            first.add(StandardKey::from(k), blue);
+        }
+        for k in 0x12..0x18 {
+            second.add(StandardKey::from(k), blue);
+        }
+        assert_eq!(device.sent_control_packets(), vec![
+            first.to_control_packet(),
+            second.to_control_packet(),
+            FlushPacket::new(&model::G910).to_control_packet(),
+        ]);
+    }
+
+    #[test]
+    fn unchanged_colors_are_not_sent() {
+        let device = FakeDevice::new();
+        let mut keyboard = keyboard(&device);
+        let red = Color::new(255, 0, 0);
+        keyboard.set_color(KeyColor::new(StandardKey::A, red)).unwrap();
+        drain(&mut keyboard);
+        let frame = keyboard.last_frame();
+        device.take_sent_control_packets();
+        keyboard.set_color(KeyColor::new(StandardKey::A, red)).unwrap();
+        drain(&mut keyboard);
+        assert!(device.take_sent_control_packets().is_empty());
+        assert_eq!(keyboard.last_frame(), frame);
+        // only the changed key is sent
+        let blue = Color::new(0, 0, 255);
+        keyboard.set_key_colors(vec![KeyColor::new(StandardKey::A, red), KeyColor::new(StandardKey::B, blue)]).unwrap();
+        drain(&mut keyboard);
+        let mut color = ColorPacket::new(&model::G910);
+        color.add(StandardKey::B, blue);
+        assert_eq!(device.take_sent_control_packets(), vec![
+            color.to_control_packet(),
+            FlushPacket::new(&model::G910).to_control_packet(),
+        ]);
+    }
+
+    #[test]
+    fn snapshot_reflects_key_and_zone_colors() {
+        let device = FakeDevice::new();
+        let mut keyboard = keyboard(&device);
+        let red = Color::new(255, 0, 0);
+        let blue = Color::new(0, 0, 255);
+        assert!(keyboard.snapshot().is_empty());
+        assert_eq!(keyboard.get_color(&Key::Standard(StandardKey::A)), None);
+        keyboard.set_zone_color(Zone::Keys, blue).unwrap();
+        keyboard.set_zone_color(Zone::GamingKeys, red).unwrap();
+        keyboard.set_color(KeyColor::new(StandardKey::A, red)).unwrap();
+        drain(&mut keyboard);
+        let snapshot = keyboard.snapshot();
+        let model = &model::G910;
+        assert_eq!(snapshot.len(), model.keys().iter().filter(|k| Zone::of(k) != Some(Zone::Logo)).count());
+        for (key, color) in snapshot {
+            let expected = match key {
+                Key::Standard(StandardKey::A) | Key::Gaming(_) => red,
+                _ => blue,
+            };
+            assert_eq!(color, expected, "{:?}", key);
+            assert_eq!(keyboard.get_color(&key), Some(expected));
+        }
+        // native effects replace the colors of their zone
+        keyboard.set_native_effect(Zone::Keys, NativeEffect::Color(blue)).unwrap();
+        assert_eq!(keyboard.get_color(&Key::Standard(StandardKey::A)), None);
+        assert_eq!(keyboard.get_color(&Key::Gaming(GamingKey::G1)), Some(red));
+    }
+
+    #[test]
+    fn dropped_frame_resends_all_colors() {
+        let device = FakeDevice::new();
+        let mut keyboard = keyboard(&device);
+        let red = Color::new(255, 0, 0);
+        keyboard.set_key_colors(vec![KeyColor::new(StandardKey::A, red), KeyColor::new(StandardKey::B, red)]).unwrap();
+        drain(&mut keyboard);
+        device.set_auto_ack(false);
+        keyboard.set_ack_timeout(Duration::from_millis(1));
+        keyboard.set_ack_retries(0);
+        keyboard.set_color(KeyColor::new(StandardKey::C, red)).unwrap();
+        ::std::thread::sleep(Duration::from_millis(5));
+        drain(&mut keyboard);
+        device.set_auto_ack(true);
+        keyboard.set_ack_timeout(Duration::from_secs(3600));
+        device.take_sent_control_packets();
+        keyboard.set_color(KeyColor::new(StandardKey::D, red)).unwrap();
+        drain(&mut keyboard);
+        let mut color = ColorPacket::new(&model::G910);
+        for &key in [StandardKey::A, StandardKey::B, StandardKey::C, StandardKey::D].iter() {
+            color.add(key, red);
+        }
+        assert_eq!(device.take_sent_control_packets(), vec![
+            color.to_control_packet(),
+            FlushPacket::new(&model::G910).to_control_packet(),
+        ]);
+    }
+
+    #[test]
+    fn colors_and_effects_are_restored_after_reconnect() {
+        let device = FakeDevice::new();
+        let mut keyboard = keyboard(&device);
+        keyboard.set_reconnect_policy(ReconnectPolicy::Fixed {
+            interval: Duration::from_millis(1),
+            attempts: 5,
+        });
+        let red = Color::new(255, 0, 0);
+        let green = Color::new(0, 255, 0);
+        let breathing = NativeEffect::Breathing {
+            color: Color::new(0, 0, 255),
+            period: Duration::from_secs(1),
+        };
+        keyboard.set_native_effect(Zone::Logo, breathing).unwrap();
+        keyboard.set_key_colors(vec![KeyColor::new(StandardKey::A, red), KeyColor::new(GamingKey::G1, green)]).unwrap();
+        drain(&mut keyboard);
+        device.take_sent_control_packets();
+        device.set_connected(false);
+        let replug = device.clone();
+        keyboard.on_reconnecting(move |attempt| if attempt == 2 {
+            replug.set_connected(true);
+        });
+        keyboard.reconnect().unwrap();
+        drain(&mut keyboard);
+        assert_eq!(device.reconnects(), 1);
+        let mut standard = ColorPacket::new(&model::G910);
+        standard.add(StandardKey::A, red);
+        let mut gaming = ColorPacket::new(&model::G910);
+        gaming.add(GamingKey::G1, green);
+        assert_eq!(device.take_sent_control_packets(), vec![
+            NativeEffectPacket::new(&model::G910, Zone::Logo, breathing).unwrap().to_control_packet(),
+            standard.to_control_packet(),
+            gaming.to_control_packet(),
+            FlushPacket::new(&model::G910).to_control_packet(),
+        ]);
+    }
+
+    #[test]
+    fn handle_loop_fails_without_auto_reconnect() {
+        let device = FakeDevice::new();
+        let mut keyboard = keyboard(&device);
+        keyboard.set_auto_reconnect(false);
+        device.set_connected(false);
+        match keyboard.start_handle_loop() {
+            Err(Error::Usb(UsbError::NoDevice)) => {},
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(device.reconnects(), 0);
+    }
+
+    #[test]
+    fn reconnect_fails_after_max_attempts() {
+        let device = FakeDevice::new();
+        let mut keyboard = keyboard(&device);
+        keyboard.set_reconnect_policy(ReconnectPolicy::Fixed {
+            interval: Duration::from_millis(1),
+            attempts: 3,
+        });
+        let attempts = Rc::new(Cell::new(0));
+        let counter = attempts.clone();
+        keyboard.on_reconnecting(move |attempt| counter.set(attempt));
+        device.set_connected(false);
+        match keyboard.reconnect() {
+            Err(Error::Usb(UsbError::NoDevice)) => {},
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[test]
+    fn reconnect_steps_dont_block() {
+        let device = FakeDevice::new();
+        let mut keyboard = keyboard(&device);
+        keyboard.set_reconnect_policy(ReconnectPolicy::Fixed {
+            interval: Duration::from_secs(3600),
+            attempts: 2,
+        });
+        keyboard.set_key_colors(vec![KeyColor::new(StandardKey::A, Color::new(255, 0, 0))]).unwrap();
+        drain(&mut keyboard);
+        device.take_sent_control_packets();
+        assert!(!keyboard.connection_lost(&Error::AckTimeout));
+        device.set_connected(false);
+        let err = keyboard.poll_events().unwrap_err();
+        assert!(keyboard.connection_lost(&err));
+        keyboard.begin_reconnect();
+        assert_eq!(keyboard.reconnect_step().unwrap(), Some(Duration::from_secs(3600)));
+        device.set_connected(true);
+        assert_eq!(keyboard.reconnect_step().unwrap(), None);
+        assert_eq!(device.reconnects(), 1);
+        drain(&mut keyboard);
+        assert_eq!(device.take_sent_control_packets().len(), 2);
+
+        device.set_connected(false);
+        keyboard.begin_reconnect();
+        assert!(keyboard.reconnect_step().unwrap().is_some());
+        match keyboard.reconnect_step() {
+            Err(Error::Usb(UsbError::NoDevice)) => {},
+            res => panic!("unexpected result {:?}", res),
+        }
+        keyboard.set_auto_reconnect(false);
+        assert!(!keyboard.connection_lost(&Error::Usb(UsbError::NoDevice)));
+    }
+
+    #[test]
+    fn brightness_is_lossless() {
+        let device = FakeDevice::new();
+        let mut keyboard = keyboard(&device);
+        let color = Color::new(255, 101, 7);
+        let logo = Color::new(3, 200, 99);
+        keyboard.set_native_effect(Zone::Logo, NativeEffect::Color(logo)).unwrap();
+        keyboard.set_key_colors(vec![KeyColor::new(StandardKey::A, color), KeyColor::new(StandardKey::B, color)]).unwrap();
+        drain(&mut keyboard);
+        let original = device.take_sent_control_packets();
+        keyboard.set_brightness(50).unwrap();
+        drain(&mut keyboard);
+        let mut dimmed = ColorPacket::new(&model::G910);
+        dimmed.add(StandardKey::A, color.scale(50));
+        dimmed.add(StandardKey::B, color.scale(50));
+        assert_eq!(device.take_sent_control_packets(), vec![
+            NativeEffectPacket::new(&model::G910, Zone::Logo, NativeEffect::Color(logo.scale(50))).unwrap()
+                .to_control_packet(),
+            dimmed.to_control_packet(),
+            FlushPacket::new(&model::G910).to_control_packet(),
+        ]);
+        // the framebuffer keeps the undimmed colors
+        assert_eq!(keyboard.get_color(&Key::Standard(StandardKey::A)), Some(color));
+        keyboard.set_brightness(100).unwrap();
+        drain(&mut keyboard);
+        assert_eq!(device.take_sent_control_packets(), original);
+    }
+}
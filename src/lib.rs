@@ -1,19 +1,67 @@
 //#![warn(missing_docs)]
 
+#[macro_use]
+extern crate log;
 extern crate libusb;
 extern crate byteorder;
 extern crate nix;
+extern crate libusb_sys;
+extern crate libc;
+#[cfg(feature = "async")]
+extern crate futures;
+#[cfg(feature = "async")]
+extern crate tokio_core;
+#[cfg(feature = "async")]
+extern crate mio;
 
+pub use error::{Error, Result};
 pub use color::{Color, KeyColor};
+pub use native_effect::{NativeEffect, Zone, WaveDirection};
 pub use keys::{Key, KeyType, StandardKey, MediaKey, GamingKey, Logo};
+pub use geometry::{Geometry, Layout, Rect};
+pub use key_selection::{KeyGroup, parse_keys};
+pub use effect::{Effect, EffectRunner, RainbowWave, Breathing, ColorCycle, GradientSweep};
 pub use keyboard::{Keyboard, KeyboardImpl};
-pub use event::{KeyEvent, HandlerBuilder, Handler};
+pub use event::{KeyEvent, HandlerBuilder, Handler, KeySink};
+pub use handle::{Transport, Handle, ControlPacket};
+pub use fake::FakeDevice;
+pub use device::{DeviceInfo, DeviceSelector, ConnectionMode, devices};
+pub use reconnect::ReconnectPolicy;
+pub use remote::{Remote, StopToken};
+pub use capture::{CaptureTransport, PcapWriter, UsbmonRecord, read_pcap};
+pub use replay::{Replay, ReplayResult, RecordedFrame};
+#[cfg(target_os = "linux")]
+pub use hidraw::{HidrawTransport, find_nodes as find_hidraw_nodes};
+#[cfg(target_os = "linux")]
+pub use uinput::{UinputSink, linux_key_code};
+#[cfg(feature = "async")]
+pub use async_keyboard::{AsyncKeyboard, ColorsAcknowledged, Reconnecting};
+pub use model::{DeviceModel, MODELS, G910, G810, G610, G410, G513, G_PRO};
 
 mod consts;
+mod error;
 mod color;
+mod native_effect;
 mod keys;
+mod geometry;
+mod key_selection;
+mod effect;
 mod utils;
+mod device;
+mod model;
+mod hotplug;
+mod reconnect;
+mod remote;
+mod capture;
+mod replay;
+#[cfg(target_os = "linux")]
+mod hidraw;
+#[cfg(target_os = "linux")]
+mod uinput;
 mod handle;
 mod keyboard;
 mod parser;
 mod event;
+mod fake;
+#[cfg(feature = "async")]
+mod async_keyboard;
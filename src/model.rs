@@ -0,0 +1,152 @@
+use keys::*;
+use consts;
+
+/// Describes the lighting capabilities and protocol details of a keyboard model.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DeviceModel {
+    pub name: &'static str,
+    pub product_ids: &'static [u16],
+    /// Head of per-key color packets: report id, device index, feature index, function.
+    pub color_header: u32,
+    /// Head of the packet committing previously sent colors.
+    pub flush_header: u32,
+    /// Head of the packet starting a native effect.
+    pub effect_header: u32,
+    /// Maximum number of key colors in a single color packet.
+    pub keys_per_packet: usize,
+    /// Whether the model has (lit) G-keys.
+    pub gaming_keys: bool,
+    /// Lit logos of the model.
+    pub logos: &'static [Logo],
+    /// Whether the model has a numpad.
+    pub numpad: bool,
+}
+
+impl DeviceModel {
+    /// Returns whether the color of given key can be set on this model.
+    pub fn supports(&self, key: &Key) -> bool {
+        match key {
+            // None only marks empty slots in reports
+            &Key::Standard(StandardKey::None) | &Key::Gaming(GamingKey::None) => false,
+            &Key::Standard(s) => self.numpad || !is_numpad(s),
+            &Key::Gaming(_) => self.gaming_keys,
+            &Key::Logo(l) => self.logos.contains(&l),
+            // media keys are not lit individually
+            &Key::Media(_) => false,
+        }
+    }
+
+    /// Returns all keys whose color can be set on this model.
+    pub fn keys(&self) -> Vec<Key> {
+        Key::values().into_iter()
+            .filter(|k| self.supports(k))
+            .collect()
+    }
+}
+
+pub fn is_numpad(key: StandardKey) -> bool {
+    let value = key as u8;
+    value >= StandardKey::NumLock as u8 && value <= StandardKey::NumComma as u8
+}
+
+pub static G910: DeviceModel = DeviceModel {
+    name: "G910",
+    // Orion Spark, Orion Spectrum
+    product_ids: &[consts::PRODUCT_ID, 0xc335],
+    color_header: 0x12ff0f3b,
+    flush_header: 0x11ff0f5b,
+    effect_header: 0x11ff103c,
+    keys_per_packet: 14,
+    gaming_keys: true,
+    logos: &[Logo::G, Logo::G910],
+    numpad: true,
+};
+
+pub static G810: DeviceModel = DeviceModel {
+    name: "G810",
+    product_ids: &[0xc331, 0xc337],
+    color_header: 0x12ff0c3a,
+    flush_header: 0x11ff0c5a,
+    effect_header: 0x11ff0d3c,
+    keys_per_packet: 14,
+    gaming_keys: false,
+    logos: &[Logo::G],
+    numpad: true,
+};
+
+pub static G610: DeviceModel = DeviceModel {
+    name: "G610",
+    product_ids: &[0xc333, 0xc338],
+    color_header: 0x12ff0c3a,
+    flush_header: 0x11ff0c5a,
+    effect_header: 0x11ff0d3c,
+    keys_per_packet: 14,
+    gaming_keys: false,
+    logos: &[],
+    numpad: true,
+};
+
+pub static G410: DeviceModel = DeviceModel {
+    name: "G410",
+    product_ids: &[0xc330],
+    color_header: 0x12ff0c3a,
+    flush_header: 0x11ff0c5a,
+    effect_header: 0x11ff0d3c,
+    keys_per_packet: 14,
+    gaming_keys: false,
+    logos: &[],
+    numpad: false,
+};
+
+pub static G513: DeviceModel = DeviceModel {
+    name: "G513",
+    product_ids: &[0xc33c],
+    color_header: 0x12ff0c3a,
+    flush_header: 0x11ff0c5a,
+    effect_header: 0x11ff0d3c,
+    keys_per_packet: 14,
+    gaming_keys: false,
+    logos: &[],
+    numpad: true,
+};
+
+pub static G_PRO: DeviceModel = DeviceModel {
+    name: "G Pro",
+    product_ids: &[0xc339],
+    color_header: 0x12ff0c3a,
+    flush_header: 0x11ff0c5a,
+    effect_header: 0x11ff0d3c,
+    keys_per_packet: 14,
+    gaming_keys: false,
+    logos: &[Logo::G],
+    numpad: false,
+};
+
+/// All supported models.
+pub static MODELS: &'static [&'static DeviceModel] = &[&G910, &G810, &G610, &G410, &G513, &G_PRO];
+
+/// Returns the model with given vendor and product id, if it is supported.
+pub fn find(vendor_id: u16, product_id: u16) -> Option<&'static DeviceModel> {
+    if vendor_id != consts::VENDOR_ID {
+        return None;
+    }
+    MODELS.iter()
+        .find(|m| m.product_ids.contains(&product_id))
+        .map(|m| *m)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn none_keys_are_unsupported() {
+        for model in MODELS {
+            assert!(!model.supports(&Key::Standard(StandardKey::None)), "{}", model.name);
+            assert!(!model.supports(&Key::Gaming(GamingKey::None)), "{}", model.name);
+            let keys = model.keys();
+            assert!(!keys.contains(&Key::Standard(StandardKey::None)), "{}", model.name);
+            assert!(!keys.contains(&Key::Gaming(GamingKey::None)), "{}", model.name);
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use std::time::Duration;
+use byteorder::{BigEndian, WriteBytesExt};
+use color::Color;
+use keys::Key;
+use handle::{ToControlPacket, ControlPacket};
+use model::DeviceModel;
+use error::{Error, Result};
+
+/// Lighting zone of the keyboard, which can be colored as a whole.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Zone {
+    /// All standard keys.
+    Keys,
+    /// All G-keys, which can't run native effects.
+    GamingKeys,
+    /// All logos.
+    Logo,
+}
+
+impl Zone {
+    /// Returns the zone given key belongs to, None for media keys which aren't lit.
+    pub fn of(key: &Key) -> Option<Zone> {
+        match key {
+            &Key::Standard(_) => Some(Zone::Keys),
+            &Key::Gaming(_) => Some(Zone::GamingKeys),
+            &Key::Logo(_) => Some(Zone::Logo),
+            &Key::Media(_) => None,
+        }
+    }
+
+    /// Returns the id of the zone in native effect packets, None if it can't run native effects.
+    pub fn effect_id(&self) -> Option<u8> {
+        match *self {
+            Zone::Keys => Some(0x00),
+            Zone::GamingKeys => None,
+            Zone::Logo => Some(0x01),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WaveDirection {
+    Horizontal = 0x01,
+    Vertical = 0x02,
+    CenterOut = 0x03,
+}
+
+/// Effect run by the keyboard's firmware, which keeps running without the host.
+///
+/// Periods are limited to 65535ms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NativeEffect {
+    /// All keys of the zone show the same color.
+    Color(Color),
+    /// The color fades in and out once per period.
+    Breathing {
+        color: Color,
+        period: Duration,
+    },
+    /// All keys cycle through all colors once per period.
+    ColorCycle {
+        period: Duration,
+    },
+    /// All colors move across the keyboard once per period.
+    ///
+    /// Logos can't show waves, so they cycle their color instead.
+    Wave {
+        direction: WaveDirection,
+        period: Duration,
+    },
+}
+
+impl NativeEffect {
+    /// Returns this effect with its color dimmed to given brightness in percent.
+    ///
+    /// `ColorCycle` and `Wave` don't have a color, so they are returned unchanged.
+    pub fn scale(&self, percent: u8) -> NativeEffect {
+        match *self {
+            NativeEffect::Color(color) => NativeEffect::Color(color.scale(percent)),
+            NativeEffect::Breathing { color, period } => NativeEffect::Breathing {
+                color: color.scale(percent),
+                period: period,
+            },
+            effect => effect,
+        }
+    }
+
+    // effect group and wave variant
+    fn kind(&self, zone: Zone) -> (u8, u8) {
+        match *self {
+            NativeEffect::Color(_) => (0x01, 0x00),
+            NativeEffect::Breathing { .. } => (0x02, 0x00),
+            NativeEffect::ColorCycle { .. } => (0x03, 0x00),
+            NativeEffect::Wave { .. } if zone == Zone::Logo => (0x03, 0x00),
+            NativeEffect::Wave { direction, .. } => (0x04, direction as u8),
+        }
+    }
+
+    fn color(&self) -> Color {
+        match *self {
+            NativeEffect::Color(color) => color,
+            NativeEffect::Breathing { color, .. } => color,
+            _ => Color::new(0, 0, 0),
+        }
+    }
+
+    fn period_millis(&self) -> u16 {
+        let period = match *self {
+            NativeEffect::Color(_) => return 0,
+            NativeEffect::Breathing { period, .. } => period,
+            NativeEffect::ColorCycle { period } => period,
+            NativeEffect::Wave { period, .. } => period,
+        };
+        let millis = period.as_secs().saturating_mul(1000) + (period.subsec_nanos() / 1_000_000) as u64;
+        if millis > u16::max_value() as u64 {
+            u16::max_value()
+        } else {
+            millis as u16
+        }
+    }
+}
+
+/// Starts a native effect on one zone.
+///
+/// The static `Color` effect sets the color of the whole zone with a single packet.
+///
+/// Layout after the 4 byte header:
+/// `zone, group, r, g, b, period (breathing), period (cycle), wave variant, 0x64, period high byte (wave), storage`,
+/// with periods in ms as big endian u16, padded with zeroes to 20 bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NativeEffectPacket {
+    model: &'static DeviceModel,
+    zone: Zone,
+    effect: NativeEffect,
+}
+
+impl NativeEffectPacket {
+    /// Returns `UnsupportedZone` if the zone can't run native effects.
+    pub fn new(model: &'static DeviceModel, zone: Zone, effect: NativeEffect) -> Result<NativeEffectPacket> {
+        if zone.effect_id().is_none() {
+            return Err(Error::UnsupportedZone(zone));
+        }
+        Ok(NativeEffectPacket {
+            model: model,
+            zone: zone,
+            effect: effect,
+        })
+    }
+}
+
+impl ToControlPacket for NativeEffectPacket {
+    fn to_control_packet(self) -> ControlPacket {
+        let (group, variant) = self.effect.kind(self.zone);
+        let color = self.effect.color();
+        let period = self.effect.period_millis();
+        let mut buf = Vec::new();
+        // head
+        buf.write_u32::<BigEndian>(self.model.effect_header).unwrap();
+        buf.write_u8(self.zone.effect_id().unwrap()).unwrap();
+        buf.write_u8(group).unwrap();
+        buf.write_u8(color.red).unwrap();
+        buf.write_u8(color.green).unwrap();
+        buf.write_u8(color.blue).unwrap();
+        // breathing period
+        buf.write_u16::<BigEndian>(period).unwrap();
+        // cycle period, the low byte is shared with the wave period
+        buf.write_u16::<BigEndian>(period).unwrap();
+        buf.write_u8(variant).unwrap();
+        buf.write_u8(0x64).unwrap();
+        buf.write_u8((period >> 8) as u8).unwrap();
+        // don't store the effect as the keyboard's default
+        buf.write_u8(0x00).unwrap();
+        buf.resize(20, 0u8);
+        ControlPacket::new(buf, 0x80, 0x21, 9, 0x0212, 0x0001, Duration::from_secs(10))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use color::Color;
+    use handle::ToControlPacket;
+    use model;
+    use super::*;
+
+    fn encode(zone: Zone, effect: NativeEffect) -> Vec<u8> {
+        NativeEffectPacket::new(&model::G910, zone, effect).unwrap().to_control_packet().buf().to_vec()
+    }
+
+    fn wave_effect(direction: WaveDirection) -> NativeEffect {
+        NativeEffect::Wave {
+            direction: direction,
+            period: Duration::from_millis(5000),
+        }
+    }
+
+    #[test]
+    fn color() {
+        assert_eq!(encode(Zone::Keys, NativeEffect::Color(Color::new(0xff, 0x80, 0x01))), vec![
+            0x11, 0xff, 0x10, 0x3c, 0x00, 0x01, 0xff, 0x80, 0x01, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x64, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]);
+    }
+
+    #[test]
+    fn breathing() {
+        let effect = NativeEffect::Breathing {
+            color: Color::new(0x00, 0xff, 0x00),
+            period: Duration::from_millis(5000),
+        };
+        assert_eq!(encode(Zone::Keys, effect), vec![
+            0x11, 0xff, 0x10, 0x3c, 0x00, 0x02, 0x00, 0xff, 0x00, 0x13,
+            0x88, 0x13, 0x88, 0x00, 0x64, 0x13, 0x00, 0x00, 0x00, 0x00,
+        ]);
+    }
+
+    #[test]
+    fn color_cycle() {
+        let effect = NativeEffect::ColorCycle {
+            period: Duration::from_millis(5000),
+        };
+        assert_eq!(encode(Zone::Keys, effect), vec![
+            0x11, 0xff, 0x10, 0x3c, 0x00, 0x03, 0x00, 0x00, 0x00, 0x13,
+            0x88, 0x13, 0x88, 0x00, 0x64, 0x13, 0x00, 0x00, 0x00, 0x00,
+        ]);
+    }
+
+    #[test]
+    fn wave() {
+        assert_eq!(encode(Zone::Keys, wave_effect(WaveDirection::Horizontal)), vec![
+            0x11, 0xff, 0x10, 0x3c, 0x00, 0x04, 0x00, 0x00, 0x00, 0x13,
+            0x88, 0x13, 0x88, 0x01, 0x64, 0x13, 0x00, 0x00, 0x00, 0x00,
+        ]);
+        assert_eq!(encode(Zone::Keys, wave_effect(WaveDirection::Vertical)), vec![
+            0x11, 0xff, 0x10, 0x3c, 0x00, 0x04, 0x00, 0x00, 0x00, 0x13,
+            0x88, 0x13, 0x88, 0x02, 0x64, 0x13, 0x00, 0x00, 0x00, 0x00,
+        ]);
+        assert_eq!(encode(Zone::Keys, wave_effect(WaveDirection::CenterOut)), vec![
+            0x11, 0xff, 0x10, 0x3c, 0x00, 0x04, 0x00, 0x00, 0x00, 0x13,
+            0x88, 0x13, 0x88, 0x03, 0x64, 0x13, 0x00, 0x00, 0x00, 0x00,
+        ]);
+    }
+
+    #[test]
+    fn wave_on_logo_cycles() {
+        assert_eq!(encode(Zone::Logo, wave_effect(WaveDirection::Horizontal)), vec![
+            0x11, 0xff, 0x10, 0x3c, 0x01, 0x03, 0x00, 0x00, 0x00, 0x13,
+            0x88, 0x13, 0x88, 0x00, 0x64, 0x13, 0x00, 0x00, 0x00, 0x00,
+        ]);
+    }
+
+    #[test]
+    fn other_models() {
+        let effect = NativeEffect::Color(Color::new(0xff, 0x80, 0x01));
+        let packet = NativeEffectPacket::new(&model::G810, Zone::Keys, effect).unwrap();
+        assert_eq!(packet.to_control_packet().buf(), &[
+            0x11, 0xff, 0x0d, 0x3c, 0x00, 0x01, 0xff, 0x80, 0x01, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x64, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ][..]);
+    }
+
+    #[test]
+    fn gaming_keys_are_unsupported() {
+        let effect = NativeEffect::Color(Color::new(0xff, 0x80, 0x01));
+        match NativeEffectPacket::new(&model::G910, Zone::GamingKeys, effect) {
+            Err(Error::UnsupportedZone(Zone::GamingKeys)) => {},
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+}
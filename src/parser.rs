@@ -2,7 +2,9 @@ use std::collections::HashSet;
 use keys::*;
 use event::KeyEvent;
 use keyboard::KeyboardInternal;
-use libusb::{Result as UsbResult, Error as UsbError};
+use error::{Error, Result};
+use utils::Hex;
+use consts;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Packet<'a> {
@@ -26,12 +28,12 @@ pub enum Parser {
 
 pub trait ParseKey {
     fn accept(&self, packet: &Packet) -> bool;
-    fn parse(&mut self, packet: &Packet, keyboard_internal: &mut KeyboardInternal) -> UsbResult<Vec<KeyEvent>>;
+    fn parse(&mut self, packet: &Packet, keyboard_internal: &mut KeyboardInternal) -> Result<Vec<KeyEvent>>;
 }
 
 pub trait ParseControl {
     fn accept(&self, packet: &Packet) -> bool;
-    fn parse(&mut self, packet: &Packet, keyboard_internal: &mut KeyboardInternal) -> UsbResult<()>;
+    fn parse(&mut self, packet: &Packet, keyboard_internal: &mut KeyboardInternal) -> Result<()>;
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -62,7 +64,7 @@ impl ParseKey for KeyParser {
     }
 
     #[allow(unused_variables)]
-    fn parse(&mut self, packet: &Packet, keyboard_internal: &mut KeyboardInternal) -> UsbResult<Vec<KeyEvent>> {
+    fn parse(&mut self, packet: &Packet, keyboard_internal: &mut KeyboardInternal) -> Result<Vec<KeyEvent>> {
         let mut state = HashSet::new();
 
         let media = packet.endpoint == 2 && packet.buf[0] == 0x02;
@@ -72,7 +74,8 @@ impl ParseKey for KeyParser {
         // media keys
         if media {
             for key in MediaKey::values() {
-                if packet.buf[1] & key as u8 == key as u8 {
+                // None has no bit, so it would always match
+                if key != MediaKey::None && packet.buf[1] & key as u8 == key as u8 {
                     state.insert(key.into());
                 }
             }
@@ -166,22 +169,22 @@ impl ParseControl for ControlParser {
         || packet.buf.len() == 20 && packet.endpoint == 2 && packet.buf[0] == 0x11
     }
 
-    fn parse(&mut self, packet: &Packet, keyboard_internal: &mut KeyboardInternal) -> UsbResult<()> {
+    fn parse(&mut self, packet: &Packet, keyboard_internal: &mut KeyboardInternal) -> Result<()> {
         if packet.buf.len() == 0 {
-            println!("buf empty");
+            debug!(target: consts::LOG_PARSER, "Empty packet on endpoint {}", packet.endpoint);
             Ok(())
         } else if packet.endpoint == 0
              && !(packet.buf[0] == 0x11 || packet.buf[0] == 0x12) {
-            println!("Trying to parse unknown packet from iface 0: {:?}", packet);
-            Err(UsbError::NotSupported)
+            warn!(target: consts::LOG_PARSER, "Unknown packet from iface 0: {}", Hex(packet.buf));
+            Err(Error::MalformedPacket(packet.buf.to_vec()))
         } else if packet.endpoint == 2
             && !(packet.buf[0] == 0x11) {
-            println!("Trying to parse unknown packet from iface 2: {:?}", packet);
-            Err(UsbError::NotSupported)
+            warn!(target: consts::LOG_PARSER, "Unknown packet from iface 2: {}", Hex(packet.buf));
+            Err(Error::MalformedPacket(packet.buf.to_vec()))
         // wait for the acknoledgement of the control packet on iface 2 before
         // sending the next one
         } else if packet.endpoint == 2 {
-            keyboard_internal.send_next_control()
+            keyboard_internal.control_acknowledged(packet.buf)
         } else {
             Ok(())
         }
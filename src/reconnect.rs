@@ -0,0 +1,146 @@
+use std::time::Duration;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// Determines how often and in which intervals reconnecting is attempted.
+///
+/// If the transport supports hotplug, waiting for the keyboard to be plugged in
+/// again doesn't count as an attempt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReconnectPolicy {
+    /// Tries `attempts` times, waiting `interval` between attempts.
+    Fixed {
+        interval: Duration,
+        attempts: u32,
+    },
+    /// Doubles the delay after each attempt, starting at `initial` up to at most `max`.
+    ///
+    /// Each delay is randomized to lie between half and the full delay,
+    /// so multiple processes don't retry in lockstep.
+    /// If `attempts` is None, retries forever.
+    ExponentialBackoff {
+        initial: Duration,
+        max: Duration,
+        attempts: Option<u32>,
+    },
+    /// Retries forever, waiting `interval` between attempts.
+    Unlimited {
+        interval: Duration,
+    },
+}
+
+impl ReconnectPolicy {
+    /// Returns the maximum number of attempts, or None if unlimited.
+    pub fn max_attempts(&self) -> Option<u32> {
+        match self {
+            &ReconnectPolicy::Fixed { attempts, .. } => Some(attempts),
+            &ReconnectPolicy::ExponentialBackoff { attempts, .. } => attempts,
+            &ReconnectPolicy::Unlimited { .. } => None,
+        }
+    }
+
+    /// Returns the time to wait after given failed attempt, starting at 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        match self {
+            &ReconnectPolicy::Fixed { interval, .. } => interval,
+            &ReconnectPolicy::Unlimited { interval } => interval,
+            &ReconnectPolicy::ExponentialBackoff { initial, max, .. } => {
+                let mut delay = initial;
+                for _ in 1..attempt {
+                    if delay >= max {
+                        break;
+                    }
+                    delay = delay * 2;
+                }
+                if delay > max {
+                    delay = max;
+                }
+                let half = delay / 2;
+                half + scale(delay - half, random_fraction())
+            }
+        }
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy::Fixed {
+            interval: Duration::from_secs(1),
+            attempts: 10,
+        }
+    }
+}
+
+fn scale(duration: Duration, factor: f64) -> Duration {
+    let nanos = duration.as_secs() as f64 * 1e9 + duration.subsec_nanos() as f64;
+    let nanos = (nanos * factor) as u64;
+    Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+}
+
+/// Returns a random number in [0, 1).
+fn random_fraction() -> f64 {
+    // RandomState is seeded randomly, which is good enough for jitter
+    // and saves us a dependency
+    let hash = RandomState::new().build_hasher().finish();
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::{ReconnectPolicy, random_fraction, scale};
+
+    #[test]
+    fn fixed_and_unlimited() {
+        let fixed = ReconnectPolicy::Fixed {
+            interval: Duration::from_millis(300),
+            attempts: 3,
+        };
+        assert_eq!(fixed.max_attempts(), Some(3));
+        for attempt in 1..4 {
+            assert_eq!(fixed.delay(attempt), Duration::from_millis(300));
+        }
+        let unlimited = ReconnectPolicy::Unlimited {
+            interval: Duration::from_secs(2),
+        };
+        assert_eq!(unlimited.max_attempts(), None);
+        assert_eq!(unlimited.delay(1000), Duration::from_secs(2));
+        assert_eq!(ReconnectPolicy::default().max_attempts(), Some(10));
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        let policy = ReconnectPolicy::ExponentialBackoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_millis(1000),
+            attempts: Some(5),
+        };
+        assert_eq!(policy.max_attempts(), Some(5));
+        let unlimited = ReconnectPolicy::ExponentialBackoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_millis(1000),
+            attempts: None,
+        };
+        assert_eq!(unlimited.max_attempts(), None);
+        // 100, 200, 400, 800, then capped at 1000
+        let full = [100, 200, 400, 800, 1000, 1000, 1000];
+        for _ in 0..100 {
+            for (i, &full) in full.iter().enumerate() {
+                let delay = policy.delay(i as u32 + 1);
+                assert!(delay >= Duration::from_millis(full / 2), "attempt {}: {:?}", i + 1, delay);
+                assert!(delay <= Duration::from_millis(full), "attempt {}: {:?}", i + 1, delay);
+            }
+        }
+        // doesn't overflow
+        assert!(policy.delay(u32::max_value()) <= Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn jitter() {
+        let fractions: Vec<_> = (0..100).map(|_| random_fraction()).collect();
+        assert!(fractions.iter().all(|&f| f >= 0.0 && f < 1.0));
+        assert!(fractions.iter().any(|&f| f != fractions[0]));
+        assert_eq!(scale(Duration::from_secs(3), 0.5), Duration::from_millis(1500));
+        assert_eq!(scale(Duration::from_secs(3), 0.0), Duration::from_secs(0));
+    }
+}
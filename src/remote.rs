@@ -0,0 +1,109 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use error::{Error, Result};
+use color::{Color, KeyColor};
+use native_effect::{NativeEffect, Zone};
+use event::Handler;
+
+pub enum Command {
+    SetKeyColors(Vec<KeyColor>),
+    SetAllColors(Color),
+    SetZoneColor(Zone, Color),
+    SetBrightness(u8),
+    SetNativeEffect(Zone, NativeEffect),
+    // handlers aren't Send, so they are created by the handle loop
+    AddHandler(u32, Box<FnMut() -> Handler + Send>),
+    RemoveHandler(u32),
+    Shutdown,
+}
+
+/// Handle to a keyboard which can be used from other threads while its handle loop is running.
+///
+/// Commands are executed by the handle loop between USB events.
+/// All methods return `RemoteClosed` if the keyboard has been dropped.
+#[derive(Clone)]
+pub struct Remote {
+    sender: Arc<Mutex<Sender<Command>>>,
+    // shared with the keyboard, so indices of handlers added remotely don't collide
+    handler_index: Arc<AtomicUsize>,
+}
+
+impl Remote {
+    pub fn new(sender: Sender<Command>, handler_index: Arc<AtomicUsize>) -> Remote {
+        Remote {
+            sender: Arc::new(Mutex::new(sender)),
+            handler_index: handler_index,
+        }
+    }
+
+    fn send(&self, command: Command) -> Result<()> {
+        self.sender.lock().unwrap().send(command).map_err(|_| Error::RemoteClosed)
+    }
+
+    pub fn set_key_colors(&self, key_colors: Vec<KeyColor>) -> Result<()> {
+        self.send(Command::SetKeyColors(key_colors))
+    }
+
+    pub fn set_color(&self, key_color: KeyColor) -> Result<()> {
+        self.send(Command::SetKeyColors(vec![key_color]))
+    }
+
+    pub fn set_all_colors(&self, color: Color) -> Result<()> {
+        self.send(Command::SetAllColors(color))
+    }
+
+    pub fn set_zone_color(&self, zone: Zone, color: Color) -> Result<()> {
+        self.send(Command::SetZoneColor(zone, color))
+    }
+
+    pub fn set_brightness(&self, percent: u8) -> Result<()> {
+        self.send(Command::SetBrightness(percent))
+    }
+
+    pub fn set_native_effect(&self, zone: Zone, effect: NativeEffect) -> Result<()> {
+        self.send(Command::SetNativeEffect(zone, effect))
+    }
+
+    /// Adds the handler created by given function, which is called on the handle loop's thread.
+    ///
+    /// Returns the index of the handler, which can be used to remove it again.
+    pub fn add_handler<F: 'static + Send + FnOnce() -> Handler>(&self, f: F) -> Result<u32> {
+        let index = self.handler_index.fetch_add(1, Ordering::SeqCst) as u32;
+        let mut f = Some(f);
+        try!(self.send(Command::AddHandler(index, Box::new(move || f.take().unwrap()()))));
+        Ok(index)
+    }
+
+    pub fn remove_handler(&self, index: u32) -> Result<()> {
+        self.send(Command::RemoveHandler(index))
+    }
+
+    /// Makes the handle loop return `Ok(())`.
+    pub fn shutdown(&self) -> Result<()> {
+        self.send(Command::Shutdown)
+    }
+}
+
+/// Makes the handle loop return `Ok(())` when stopped, e.g. from another thread.
+#[derive(Clone)]
+pub struct StopToken(Arc<AtomicBool>);
+
+impl StopToken {
+    pub fn new() -> StopToken {
+        StopToken(Arc::new(AtomicBool::new(false)))
+    }
+
+    pub fn stop(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Clears a stop request, so the handle loop can be started again.
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
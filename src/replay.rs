@@ -0,0 +1,175 @@
+use std::time::Duration;
+use error::Result;
+use handle::ControlPacket;
+use event::{KeyEvent, Handler};
+use keyboard::KeyboardImpl;
+use fake::FakeDevice;
+use model::{self, DeviceModel};
+
+/// A transfer recorded from the keyboard.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedFrame {
+    /// Endpoint including the direction bit.
+    pub endpoint: u8,
+    pub buf: Vec<u8>,
+    /// Time since the start of the recording.
+    pub timestamp: Duration,
+}
+
+impl RecordedFrame {
+    pub fn new(endpoint: u8, buf: Vec<u8>, timestamp: Duration) -> RecordedFrame {
+        RecordedFrame {
+            endpoint: endpoint,
+            buf: buf,
+            timestamp: timestamp,
+        }
+    }
+}
+
+/// Everything which resulted from replaying a recording.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayResult {
+    /// All parsed key events with the timestamp of the frame they resulted from.
+    pub events: Vec<(Duration, KeyEvent)>,
+    /// All control packets sent to the keyboard, e.g. by handlers.
+    pub control_packets: Vec<ControlPacket>,
+}
+
+/// Feeds recorded frames through the same parsers and handlers the handle loop uses,
+/// without needing a keyboard.
+///
+/// The recording should contain the keyboard's acknowledgements, as they
+/// aren't generated automatically during replay.
+pub struct Replay {
+    keyboard: KeyboardImpl,
+    device: FakeDevice,
+}
+
+impl Replay {
+    pub fn new() -> Replay {
+        Replay::with_model(&model::G910)
+    }
+
+    pub fn with_model(model: &'static DeviceModel) -> Replay {
+        let device = FakeDevice::with_model(model);
+        device.set_auto_ack(false);
+        Replay {
+            keyboard: KeyboardImpl::with_transport(Box::new(device.clone())),
+            device: device,
+        }
+    }
+
+    pub fn add_handler(&mut self, handler: Handler) -> u32 {
+        self.keyboard.add_handler(handler)
+    }
+
+    /// Returns the keyboard frames are fed into, e.g. to set its colors before replaying.
+    pub fn keyboard(&mut self) -> &mut KeyboardImpl {
+        &mut self.keyboard
+    }
+
+    /// Initializes all handlers and dispatches given frames in order.
+    pub fn run(&mut self, frames: &[RecordedFrame]) -> Result<ReplayResult> {
+        // don't report packets sent before replaying
+        self.device.take_sent_control_packets();
+        try!(self.keyboard.init_handlers());
+        let mut events = Vec::new();
+        for frame in frames {
+            for event in try!(self.keyboard.dispatch(frame.endpoint, &frame.buf)) {
+                events.push((frame.timestamp, event));
+            }
+        }
+        Ok(ReplayResult {
+            events: events,
+            control_packets: self.device.take_sent_control_packets(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+    use capture::{PcapWriter, UsbmonRecord, read_pcap};
+    use color::{Color, ColorPacket, FlushPacket, KeyColor};
+    use event::{HandlerBuilder, KeyEvent};
+    use handle::ToControlPacket;
+    use keys::{Key, MediaKey, StandardKey};
+    use model;
+    use super::{RecordedFrame, Replay};
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn padded(buf: &[u8], len: usize) -> Vec<u8> {
+        let mut buf = buf.to_vec();
+        buf.resize(len, 0u8);
+        buf
+    }
+
+    #[test]
+    fn run() {
+        let mut replay = Replay::new();
+        replay.add_handler(HandlerBuilder::new(())
+            .accept_key_fn(|_, event| *event == KeyEvent::KeyPressed(Key::Standard(StandardKey::A)))
+            .handle_key_fn(|_, _, keyboard| keyboard.set_color(KeyColor::new(StandardKey::A, Color::new(255, 0, 0))))
+            .build());
+        let frames = vec![
+            // A pressed
+            RecordedFrame::new(0x81, vec![0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00], ms(0)),
+            // ack of the color packet sent by the handler
+            RecordedFrame::new(0x82, padded(&[0x11, 0xff, 0x0f, 0x3b], 20), ms(10)),
+            // A released
+            RecordedFrame::new(0x81, vec![0x00; 8], ms(20)),
+            // B pressed in the rollover report
+            RecordedFrame::new(0x82, padded(&[0x01, 0x05], 21), ms(30)),
+            // play/pause pressed
+            RecordedFrame::new(0x82, vec![0x02, 0x08], ms(40)),
+        ];
+        let result = replay.run(&frames).unwrap();
+        assert_eq!(result.events, vec![
+            (ms(0), KeyEvent::KeyPressed(Key::Standard(StandardKey::A))),
+            (ms(20), KeyEvent::KeyReleased(Key::Standard(StandardKey::A))),
+            (ms(30), KeyEvent::KeyPressed(Key::Standard(StandardKey::B))),
+            (ms(40), KeyEvent::KeyPressed(Key::Media(MediaKey::PlayPause))),
+        ]);
+        let mut color = ColorPacket::new(&model::G910);
+        color.add(StandardKey::A, Color::new(255, 0, 0));
+        assert_eq!(result.control_packets, vec![
+            color.to_control_packet(),
+            FlushPacket::new(&model::G910).to_control_packet(),
+        ]);
+    }
+
+    #[test]
+    fn read_pcap_round_trip() {
+        let start = UNIX_EPOCH + Duration::from_secs(1000);
+        let record = |id, submit, endpoint, data: Vec<u8>, timestamp| UsbmonRecord {
+            id: id,
+            submit: submit,
+            control: endpoint == 0x80,
+            endpoint: endpoint,
+            bus_number: 1,
+            device_address: 5,
+            setup: None,
+            length: 64,
+            data: data,
+            timestamp: timestamp,
+        };
+        let ack = padded(&[0x11, 0xff, 0x0f, 0x3b], 20);
+        let mut buf = Vec::new();
+        {
+            let mut writer = PcapWriter::new(&mut buf).unwrap();
+            // only completed transfers from the keyboard are read back
+            writer.write(&record(1, true, 0x81, Vec::new(), start)).unwrap();
+            writer.write(&record(2, true, 0x02, vec![0x12, 0xff], start + ms(1))).unwrap();
+            writer.write(&record(1, false, 0x81, vec![0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00],
+                                 start + ms(2))).unwrap();
+            writer.write(&record(3, false, 0x82, ack.clone(), start + ms(3))).unwrap();
+        }
+        assert_eq!(read_pcap(&buf[..]).unwrap(), vec![
+            RecordedFrame::new(0x81, vec![0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00], ms(2)),
+            RecordedFrame::new(0x82, ack, ms(3)),
+        ]);
+    }
+}
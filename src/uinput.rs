@@ -0,0 +1,256 @@
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::slice;
+use libc;
+use error::{Error, Result};
+use keys::*;
+use event::{KeyEvent, KeySink};
+use consts;
+
+// see linux/uinput.h and linux/input-event-codes.h
+const UI_SET_EVBIT: libc::c_ulong = 0x40045564;
+const UI_SET_KEYBIT: libc::c_ulong = 0x40045565;
+const UI_DEV_CREATE: libc::c_ulong = 0x5501;
+const UI_DEV_DESTROY: libc::c_ulong = 0x5502;
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_REP: u16 = 0x14;
+const SYN_REPORT: u16 = 0x00;
+const BUS_USB: u16 = 0x03;
+
+#[repr(C)]
+struct InputId {
+    bustype: u16,
+    vendor: u16,
+    product: u16,
+    version: u16,
+}
+
+#[repr(C)]
+struct UinputUserDev {
+    name: [u8; 80],
+    id: InputId,
+    ff_effects_max: u32,
+    absmax: [i32; 64],
+    absmin: [i32; 64],
+    absfuzz: [i32; 64],
+    absflat: [i32; 64],
+}
+
+#[repr(C)]
+struct InputEvent {
+    time: libc::timeval,
+    type_: u16,
+    code: u16,
+    value: i32,
+}
+
+/// Emits key events on a virtual uinput keyboard, so the OS receives them
+/// even though the real keyboard is claimed.
+///
+/// Requires write access to `/dev/uinput`.
+pub struct UinputSink {
+    file: File,
+    // only release keys we have pressed, so swallowing a key press doesn't
+    // result in a stray release and vice versa
+    pressed: HashSet<u16>,
+}
+
+impl UinputSink {
+    pub fn new() -> Result<UinputSink> {
+        let mut file = try!(OpenOptions::new().write(true).open("/dev/uinput"));
+        let fd = file.as_raw_fd();
+        try!(ioctl(fd, UI_SET_EVBIT, EV_KEY as libc::c_int));
+        // let the kernel handle key repeat
+        try!(ioctl(fd, UI_SET_EVBIT, EV_REP as libc::c_int));
+        for key in Key::values() {
+            match linux_key_code(&key) {
+                Some(code) => try!(ioctl(fd, UI_SET_KEYBIT, code as libc::c_int)),
+                None => {}
+            }
+        }
+        let mut dev = UinputUserDev {
+            name: [0; 80],
+            id: InputId {
+                bustype: BUS_USB,
+                vendor: consts::VENDOR_ID,
+                product: consts::PRODUCT_ID,
+                version: 1,
+            },
+            ff_effects_max: 0,
+            absmax: [0; 64],
+            absmin: [0; 64],
+            absfuzz: [0; 64],
+            absflat: [0; 64],
+        };
+        let name = b"Logitech G910 (g910-rs passthrough)";
+        dev.name[..name.len()].copy_from_slice(name);
+        try!(file.write_all(as_bytes(&dev)));
+        try!(ioctl(fd, UI_DEV_CREATE, 0));
+        Ok(UinputSink {
+            file: file,
+            pressed: HashSet::new(),
+        })
+    }
+
+    fn write_event(&mut self, type_: u16, code: u16, value: i32) -> Result<()> {
+        let event = InputEvent {
+            // filled in by the kernel
+            time: libc::timeval { tv_sec: 0, tv_usec: 0 },
+            type_: type_,
+            code: code,
+            value: value,
+        };
+        self.file.write_all(as_bytes(&event)).map_err(Error::from)
+    }
+}
+
+impl KeySink for UinputSink {
+    fn emit(&mut self, event: &KeyEvent) -> Result<()> {
+        let (key, pressed) = match event {
+            &KeyEvent::KeyPressed(ref key) => (key, true),
+            &KeyEvent::KeyReleased(ref key) => (key, false),
+        };
+        let code = match linux_key_code(key) {
+            Some(code) => code,
+            None => return Ok(()),
+        };
+        if pressed {
+            self.pressed.insert(code);
+        } else if !self.pressed.remove(&code) {
+            return Ok(());
+        }
+        try!(self.write_event(EV_KEY, code, if pressed { 1 } else { 0 }));
+        self.write_event(EV_SYN, SYN_REPORT, 0)
+    }
+}
+
+impl Drop for UinputSink {
+    fn drop(&mut self) {
+        let _ = ioctl(self.file.as_raw_fd(), UI_DEV_DESTROY, 0);
+    }
+}
+
+fn as_bytes<T>(t: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(t as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+fn ioctl(fd: libc::c_int, request: libc::c_ulong, value: libc::c_int) -> Result<()> {
+    if unsafe { libc::ioctl(fd, request as _, value) } < 0 {
+        Err(io::Error::last_os_error().into())
+    } else {
+        Ok(())
+    }
+}
+
+// HID usage id to linux key code, same as the kernel's hid-input table
+static HID_TO_LINUX: [u16; 0x66] = [
+      0,   0,   0,   0,  30,  48,  46,  32,  18,  33,  34,  35,  23,  36,  37,  38,
+     50,  49,  24,  25,  16,  19,  31,  20,  22,  47,  17,  45,  21,  44,   2,   3,
+      4,   5,   6,   7,   8,   9,  10,  11,  28,   1,  14,  15,  57,  12,  13,  26,
+     27,  43,  43,  39,  40,  41,  51,  52,  53,  58,  59,  60,  61,  62,  63,  64,
+     65,  66,  67,  68,  87,  88,  99,  70, 119, 110, 102, 104, 111, 107, 109, 106,
+    105, 108, 103,  69,  98,  55,  74,  78,  96,  79,  80,  81,  75,  76,  77,  71,
+     72,  73,  82,  83,  86, 127,
+];
+
+/// Returns the linux input event code of given key, if it has one.
+///
+/// G-keys and logos don't have key codes.
+pub fn linux_key_code(key: &Key) -> Option<u16> {
+    match key {
+        &Key::Standard(StandardKey::None) => None,
+        &Key::Standard(s) => {
+            let usage = s as u8;
+            let code = match usage {
+                0x87 => 89, // KEY_RO
+                0x88 => 93, // KEY_KATAKANAHIRAGANA
+                0x89 => 124, // KEY_YEN
+                0x8a => 92, // KEY_HENKAN
+                0x8b => 94, // KEY_MUHENKAN
+                0xe0 => 29, // KEY_LEFTCTRL
+                0xe1 => 42, // KEY_LEFTSHIFT
+                0xe2 => 56, // KEY_LEFTALT
+                0xe3 => 125, // KEY_LEFTMETA
+                0xe4 => 97, // KEY_RIGHTCTRL
+                0xe5 => 54, // KEY_RIGHTSHIFT
+                0xe6 => 100, // KEY_RIGHTALT
+                0xe7 => 126, // KEY_RIGHTMETA
+                u if (u as usize) < HID_TO_LINUX.len() => HID_TO_LINUX[u as usize],
+                _ => 0,
+            };
+            if code == 0 {
+                None
+            } else {
+                Some(code)
+            }
+        },
+        &Key::Media(m) => match m {
+            MediaKey::None => None,
+            MediaKey::Forward => Some(163), // KEY_NEXTSONG
+            MediaKey::Backward => Some(165), // KEY_PREVIOUSSONG
+            MediaKey::Stop => Some(166), // KEY_STOPCD
+            MediaKey::PlayPause => Some(164), // KEY_PLAYPAUSE
+            MediaKey::VolumeUp => Some(115), // KEY_VOLUMEUP
+            MediaKey::VolumeDown => Some(114), // KEY_VOLUMEDOWN
+            MediaKey::Mute => Some(113), // KEY_MUTE
+        },
+        &Key::Gaming(_) | &Key::Logo(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use keys::*;
+    use keys::StandardKey as S;
+    use super::linux_key_code;
+
+    #[test]
+    fn hid_to_linux() {
+        let tests: Vec<(Key, Option<u16>)> = vec![
+            (S::A.into(), Some(30)),
+            // named after the german layout
+            (S::Z.into(), Some(21)),
+            (S::Y.into(), Some(44)),
+            (S::_1.into(), Some(2)),
+            (S::_0.into(), Some(11)),
+            (S::Return.into(), Some(28)),
+            (S::Esc.into(), Some(1)),
+            (S::Space.into(), Some(57)),
+            (S::F1.into(), Some(59)),
+            (S::F12.into(), Some(88)),
+            (S::Pipe.into(), Some(43)),
+            (S::Sharp.into(), Some(43)),
+            (S::SmallerThan.into(), Some(86)),
+            (S::Menu.into(), Some(127)),
+            (S::Up.into(), Some(103)),
+            (S::NumReturn.into(), Some(96)),
+            (S::Num0.into(), Some(82)),
+            (S::NumComma.into(), Some(83)),
+            (S::LeftControl.into(), Some(29)),
+            (S::RightWindows.into(), Some(126)),
+            (MediaKey::Mute.into(), Some(113)),
+            (MediaKey::PlayPause.into(), Some(164)),
+            (S::None.into(), None),
+            (MediaKey::None.into(), None),
+            (GamingKey::G1.into(), None),
+            (Logo::G.into(), None),
+        ];
+        for (key, code) in tests {
+            assert_eq!(linux_key_code(&key), code, "{:?}", key);
+        }
+    }
+
+    #[test]
+    fn every_key_has_a_code() {
+        for key in StandardKey::values().into_iter().filter(|&k| k != StandardKey::None) {
+            assert!(linux_key_code(&key.into()).is_some(), "{:?}", key);
+        }
+        for key in MediaKey::values().into_iter().filter(|&k| k != MediaKey::None) {
+            assert!(linux_key_code(&key.into()).is_some(), "{:?}", key);
+        }
+    }
+}
@@ -1,24 +1,38 @@
+use std::fmt;
+use std::fs;
+use std::ptr;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::os::unix::io::RawFd;
+use libc::{self, c_int};
+use libusb_sys as ffi;
 use libusb::{
     LogLevel,
     Context,
+    Device,
+    DeviceDescriptor,
     DeviceHandle,
     AsyncGroup,
     Result as UsbResult,
     Error,
 };
 
+use device::{DeviceInfo, DeviceSelector, ConnectionMode};
+use model;
 use consts;
 
 pub struct UsbWrapper {
     context: &'static Context,
     pub handle: &'static DeviceHandle<'static>,
+    mode: ConnectionMode,
     has_kernel_driver0: bool,
     has_kernel_driver1: bool,
     pub async_group: &'static mut AsyncGroup<'static>,
+    pub info: DeviceInfo,
 }
 
 impl UsbWrapper {
-    pub fn new() -> UsbResult<UsbWrapper> {
+    pub fn new(selector: &DeviceSelector, mode: ConnectionMode) -> UsbResult<UsbWrapper> {
         // We must leak both context and handle and async_group, as rust does not allow sibling structs.
         // Leaking them gives us a &'static reference, which we can then use without
         // lifetime bounds, as it outlives everything.
@@ -27,7 +41,13 @@ impl UsbWrapper {
         let context = try!(get_context());
         let context_ptr = Box::into_raw(Box::new(context));
         let context_ref = unsafe { &*context_ptr as &'static Context };
-        let (handle, driver0, driver1) = try!(get_handle(context_ref));
+        let (handle, info, driver0, driver1) = match get_handle(context_ref, selector, mode) {
+            Ok(res) => res,
+            Err(e) => {
+                drop(unsafe { Box::from_raw(context_ptr) });
+                return Err(e);
+            }
+        };
         let async_group = AsyncGroup::new(context_ref);
         let handle_ptr = Box::into_raw(Box::new(handle));
         let async_ptr = Box::into_raw(Box::new(async_group));
@@ -35,19 +55,52 @@ impl UsbWrapper {
             Ok(UsbWrapper {
                 context: context_ref,
                 handle: &mut *handle_ptr as &'static mut DeviceHandle<'static>,
+                mode: mode,
                 has_kernel_driver0: driver0,
                 has_kernel_driver1: driver1,
                 async_group: &mut *async_ptr as &'static mut AsyncGroup<'static>,
+                info: info,
             })
         }
     }
 }
 
+impl UsbWrapper {
+    /// Returns the file descriptors libusb waits on together with the poll events of interest.
+    ///
+    /// libusb-rs doesn't expose the raw context needed for `libusb_get_pollfds`,
+    /// so we look up the usbfs file libusb opened for the keyboard instead.
+    /// usbfs signals completed transfers with POLLOUT.
+    pub fn pollfds(&self) -> Vec<(RawFd, i16)> {
+        let path = PathBuf::from(format!("/dev/bus/usb/{:03}/{:03}", self.info.bus_number, self.info.address));
+        let mut res = Vec::new();
+        let entries = match fs::read_dir("/proc/self/fd") {
+            Ok(entries) => entries,
+            Err(_) => return res,
+        };
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            match fs::read_link(entry.path()) {
+                Ok(ref target) if *target == path => {},
+                _ => continue,
+            }
+            match entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+                Some(fd) => res.push((fd, libc::POLLOUT)),
+                None => {}
+            }
+        }
+        res
+    }
+}
+
 macro_rules! unwrap_safe {
     ($e:expr) => {
         match $e {
             Ok(_) => {},
-            Err(e) => println!("Error while dropping UsbWrapper during another panic: {:?}", e),
+            Err(e) => warn!(target: consts::LOG_TRANSPORT, "Error while dropping UsbWrapper: {:?}", e),
         }
     }
 }
@@ -59,7 +112,9 @@ impl Drop for UsbWrapper {
         {
             let handle_mut = unsafe { &mut *(self.handle as *const _ as *mut DeviceHandle<'static>) };
             unwrap_safe!(handle_mut.release_interface(1));
-            unwrap_safe!(handle_mut.release_interface(0));
+            if self.mode == ConnectionMode::Full {
+                unwrap_safe!(handle_mut.release_interface(0));
+            }
             if self.has_kernel_driver1 {
                 unwrap_safe!(handle_mut.attach_kernel_driver(1));
             }
@@ -78,7 +133,22 @@ impl Drop for UsbWrapper {
     }
 }
 
-fn get_context() -> UsbResult<Context> {
+/// Formats bytes as space separated hex, e.g. for logging packets.
+pub struct Hex<'a>(pub &'a [u8]);
+
+impl<'a> fmt::Display for Hex<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, b) in self.0.iter().enumerate() {
+            if i > 0 {
+                try!(write!(f, " "));
+            }
+            try!(write!(f, "{:02x}", b));
+        }
+        Ok(())
+    }
+}
+
+pub fn get_context() -> UsbResult<Context> {
     let mut context = try!(Context::new());
     context.set_log_level(LogLevel::Debug);
     context.set_log_level(LogLevel::Info);
@@ -88,17 +158,115 @@ fn get_context() -> UsbResult<Context> {
     Ok(context)
 }
 
-fn get_handle<'a>(context: &'a Context) -> UsbResult<(DeviceHandle<'a>, bool, bool)> {
+fn is_keyboard(dd: &DeviceDescriptor) -> bool {
+    model::find(dd.vendor_id(), dd.product_id()).is_some()
+}
+
+/// Returns the port numbers of all connected devices by bus number and address.
+///
+/// libusb-rs doesn't expose `libusb_get_port_numbers`, so the devices are listed
+/// again with a raw context.
+fn port_numbers() -> HashMap<(u8, u8), Vec<u8>> {
+    let mut res = HashMap::new();
+    unsafe {
+        let mut context = ptr::null_mut();
+        if ffi::libusb_init(&mut context) != ffi::LIBUSB_SUCCESS {
+            return res;
+        }
+        let mut list = ptr::null();
+        let len = ffi::libusb_get_device_list(context, &mut list);
+        for i in 0..len {
+            let device = *list.offset(i);
+            // USB 3.0 allows at most 7 tiers
+            let mut ports = [0u8; 7];
+            let count = ffi::libusb_get_port_numbers(device, ports.as_mut_ptr(), ports.len() as c_int);
+            if count > 0 {
+                let key = (ffi::libusb_get_bus_number(device), ffi::libusb_get_device_address(device));
+                res.insert(key, ports[..count as usize].to_vec());
+            }
+        }
+        if len >= 0 {
+            ffi::libusb_free_device_list(list, 1);
+        }
+        ffi::libusb_exit(context);
+    }
+    res
+}
+
+// the serial number is filled in by the caller, as the device must be opened to read it
+fn device_info(device: &Device, dd: &DeviceDescriptor, ports: &HashMap<(u8, u8), Vec<u8>>) -> DeviceInfo {
+    let (bus_number, address) = (device.bus_number(), device.address());
+    DeviceInfo {
+        product_id: dd.product_id(),
+        bus_number: bus_number,
+        address: address,
+        port_numbers: ports.get(&(bus_number, address)).cloned().unwrap_or(Vec::new()),
+        serial: None,
+    }
+}
+
+fn read_serial(handle: &DeviceHandle, dd: &DeviceDescriptor) -> Option<String> {
+    handle.read_serial_number_string_ascii(dd).ok()
+        .and_then(|s| if s.len() > 0 { Some(s) } else { None })
+}
+
+pub fn list_devices(context: &Context) -> UsbResult<Vec<DeviceInfo>> {
+    let devices = try!(context.devices());
+    let ports = port_numbers();
+    let mut res = Vec::new();
+    for d in devices.iter() {
+        let dd = match d.device_descriptor() {
+            Ok(dd) => dd,
+            Err(_) => continue
+        };
+        if is_keyboard(&dd) {
+            let mut info = device_info(&d, &dd, &ports);
+            // we need to open the device to read its serial number
+            info.serial = d.open().ok().and_then(|h| read_serial(&h, &dd));
+            res.push(info);
+        }
+    }
+    Ok(res)
+}
+
+fn get_handle<'a>(context: &'a Context, selector: &DeviceSelector, mode: ConnectionMode)
+        -> UsbResult<(DeviceHandle<'a>, DeviceInfo, bool, bool)> {
     let devices = try!(context.devices());
+    let ports = port_numbers();
+    let mut last_err = Error::NoDevice;
     for d in devices.iter() {
         let dd = match d.device_descriptor() {
             Ok(dd) => dd,
             Err(_) => continue
         };
-        if dd.vendor_id() == consts::VENDOR_ID && dd.product_id() == consts::PRODUCT_ID {
-            let mut handle = try!(d.open());
+        if is_keyboard(&dd) {
+            let mut info = device_info(&d, &dd, &ports);
+            // only open other keyboards if we need their serial number to match them
+            match *selector {
+                DeviceSelector::Serial(_) => {},
+                _ if !selector.matches(&info) => continue,
+                _ => {}
+            }
+            let mut handle = match d.open() {
+                Ok(handle) => handle,
+                Err(e) => {
+                    last_err = e;
+                    continue;
+                }
+            };
+            // the serial number lets us find the keyboard again after it has been replugged
+            info.serial = read_serial(&handle, &dd);
+            if !selector.matches(&info) {
+                continue;
+            }
             // for some reason we cannot claim interface 2 as it doesn't exist
             // but we will be able to read from it, if we claim interface 1
+            if mode == ConnectionMode::LightingOnly {
+                let has_kernel_driver1 = try!(detach(&mut handle, 1));
+                try!(handle.claim_interface(1));
+                // don't reset, as that would interrupt the kernel driver of interface 0
+                return Ok((handle, info, false, has_kernel_driver1));
+            }
             // detch kernel driver
             let has_kernel_driver0 = try!(detach(&mut handle, 0));
             let has_kernel_driver1 = try!(detach(&mut handle, 1));
@@ -107,10 +275,10 @@ fn get_handle<'a>(context: &'a Context) -> UsbResult<(DeviceHandle<'a>, bool, bo
             try!(handle.claim_interface(1));
             // reset keyboard to get clean status
             try!(handle.reset());
-            return Ok((handle, has_kernel_driver0, has_kernel_driver1));
+            return Ok((handle, info, has_kernel_driver0, has_kernel_driver1));
         }
     }
-    Err(Error::NoDevice)
+    Err(last_err)
 }
 
 fn detach(handle: &mut DeviceHandle, iface: u8) -> UsbResult<bool> {